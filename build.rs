@@ -6,30 +6,25 @@
 use std::{
     env,
     fs::{self, File},
-    io::{self, Read, Write},
+    io::{self, Read},
     path::Path,
     process::Command,
 };
 
 fn main() {
-    match write_version_file() {
+    match export_version_info() {
         Ok(_) => {}
-        Err(e) => panic!("Failed to create a version file: {:?}", e),
+        Err(e) => panic!("Failed to get the version information: {:?}", e),
     }
 }
 
-fn write_version_file() -> io::Result<()> {
-    let version = env::var("CARGO_PKG_VERSION").unwrap();
+/// Expose the target and revision to the crate, to be read with `env!`
+fn export_version_info() -> io::Result<()> {
     let target = env::var("TARGET").unwrap();
-    let version_fname = Path::new(&env::var("OUT_DIR").unwrap()).join("version_string");
-    let mut version_file = File::create(&version_fname)?;
-    write!(
-        &mut version_file,
-        "r#\"ion {} ({})\nrev {}\"#",
-        version,
-        target,
-        get_git_rev()?.trim()
-    )?;
+    let rev = get_git_rev()?;
+    let rev = if rev.trim().is_empty() { "unknown" } else { rev.trim() };
+    println!("cargo:rustc-env=ION_TARGET={}", target);
+    println!("cargo:rustc-env=ION_GIT_REV={}", rev);
     Ok(())
 }
 
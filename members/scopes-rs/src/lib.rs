@@ -37,6 +37,11 @@ impl<K: Hash + Eq, V> DerefMut for Scope<K, V> {
     fn deref_mut(&mut self) -> &mut Self::Target { &mut self.vars }
 }
 
+impl<K: Hash + Eq, V> Scope<K, V> {
+    /// Indicates if this scope is on a namespace boundary
+    pub fn is_namespace(&self) -> bool { self.namespace }
+}
+
 impl<K: Hash + Eq, V: Clone> Scopes<K, V> {
     pub fn with_capacity(cap: usize) -> Self {
         Self {
@@ -46,6 +51,18 @@ impl<K: Hash + Eq, V: Clone> Scopes<K, V> {
         }
     }
 
+    /// Indicates if all the given flag bits are set
+    pub fn has_flags(&self, flags: u8) -> bool { self.flags & flags == flags }
+
+    /// Set or clear the given flag bits
+    pub fn set_flags(&mut self, flags: u8, enabled: bool) {
+        if enabled {
+            self.flags |= flags;
+        } else {
+            self.flags &= !flags;
+        }
+    }
+
     pub fn new_scope(&mut self, namespace: bool) {
         self.current += 1;
         if self.current >= self.scopes.len() {
@@ -71,6 +88,35 @@ impl<K: Hash + Eq, V: Clone> Scopes<K, V> {
         self.scopes.extend(scopes);
     }
 
+    /// Clone the active scopes, from the global scope up to the current one
+    pub fn snapshot(&self) -> Vec<Scope<K, V>>
+    where
+        K: Clone,
+    {
+        self.scopes[..=self.current].to_vec()
+    }
+
+    /// Replace the whole stack with scopes taken by `snapshot`, the last one becoming current
+    pub fn restore(&mut self, scopes: Vec<Scope<K, V>>) {
+        self.scopes = scopes;
+        if self.scopes.is_empty() {
+            self.scopes.push(Scope { vars: HashMap::with_capacity(64), namespace: false });
+        }
+        self.current = self.scopes.len() - 1;
+    }
+
+    /// Swap the variables of the current scope, returning the previous ones
+    pub fn replace_current(&mut self, vars: HashMap<K, V>) -> HashMap<K, V> {
+        std::mem::replace(&mut self.scopes[self.current].vars, vars)
+    }
+
+    /// All the scopes from the global one, including the popped scopes after the current one,
+    /// which are kept to be reused
+    pub fn all_scopes(&self) -> &[Scope<K, V>] { &self.scopes }
+
+    /// The index of the current scope, 0 being the global scope
+    pub fn current_index(&self) -> usize { self.current }
+
     pub fn scopes(&self) -> impl DoubleEndedIterator<Item = &Scope<K, V>> {
         let amount = self.scopes.len() - self.current - 1;
         self.scopes.iter().rev().skip(amount)
@@ -95,6 +141,40 @@ impl<K: Hash + Eq, V: Clone> Scopes<K, V> {
         None
     }
 
+    /// The index of the scope holding `name` in the given namespace, resolved like `get`
+    fn index_in_namespace<Q: ?Sized>(&self, name: &Q, namespace: Namespace) -> Option<usize>
+    where
+        K: Borrow<Q>,
+        Q: Hash + Eq,
+    {
+        let visible = &self.scopes[..=self.current];
+        match namespace {
+            Namespace::Global => visible
+                .iter()
+                .enumerate()
+                .take_while(|(_, scope)| !scope.namespace)
+                .filter(|(_, scope)| scope.contains_key(name))
+                .map(|(i, _)| i)
+                .last(),
+            Namespace::Specific(mut up) => {
+                for (i, scope) in visible.iter().enumerate().rev() {
+                    if up == 0 {
+                        if scope.contains_key(name) {
+                            return Some(i);
+                        } else if scope.namespace {
+                            return None;
+                        }
+                    } else if scope.namespace {
+                        up -= 1;
+                    }
+                }
+
+                None
+            }
+            Namespace::Any => visible.iter().rposition(|scope| scope.contains_key(name)),
+        }
+    }
+
     pub fn set<T: Into<K>, S: Into<V>>(&mut self, name: T, value: S) -> Option<V> {
         self.scopes[self.current].insert(name.into(), value.into())
     }
@@ -130,6 +210,46 @@ impl<K: Hash + Eq, V: Clone> Scopes<K, V> {
         }
     }
 
+    /// Get a mutable reference to a variable of an explicit namespace, such as the one designated
+    /// by `global::`
+    pub fn get_mut_in_namespace<Q: ?Sized>(
+        &mut self,
+        name: &Q,
+        namespace: Namespace,
+    ) -> Option<&mut V>
+    where
+        K: Borrow<Q>,
+        Q: Hash + Eq,
+    {
+        let index = self.index_in_namespace(name, namespace)?;
+        self.scopes[index].get_mut(name)
+    }
+
+    /// Create a variable in an explicit namespace: in the global scope, or in the scope right
+    /// outside the requested namespace boundary. Returns false if there are not as many
+    /// namespaces as requested
+    pub fn set_in_namespace(&mut self, name: K, value: V, namespace: Namespace) -> bool {
+        let index = match namespace {
+            Namespace::Global => Some(0),
+            Namespace::Specific(mut up) => (0..=self.current).rev().find(|&i| {
+                if up == 0 {
+                    return true;
+                } else if self.scopes[i].namespace {
+                    up -= 1;
+                }
+                false
+            }),
+            Namespace::Any => Some(self.current),
+        };
+        match index {
+            Some(index) => {
+                self.scopes[index].insert(name, value);
+                true
+            }
+            None => false,
+        }
+    }
+
     pub fn get_mut<Q: ?Sized>(&mut self, name: &Q) -> Option<&mut V>
     where
         K: Borrow<Q>,
@@ -147,6 +267,25 @@ impl<K: Hash + Eq, V: Clone> Scopes<K, V> {
         None
     }
 
+    /// Rename a variable in the scope where it is defined, without crossing a namespace boundary
+    pub fn rename_variable<Q: ?Sized>(&mut self, old: &Q, new: K) -> bool
+    where
+        K: Borrow<Q>,
+        Q: Hash + Eq,
+    {
+        for scope in self.scopes_mut() {
+            let exit = scope.namespace;
+            if let Some(value) = scope.remove(old) {
+                scope.insert(new, value);
+                return true;
+            }
+            if exit {
+                break;
+            }
+        }
+        false
+    }
+
     pub fn remove_variable<Q: ?Sized>(&mut self, name: &Q) -> Option<V>
     where
         K: Borrow<Q>,
@@ -163,4 +302,14 @@ impl<K: Hash + Eq, V: Clone> Scopes<K, V> {
         }
         None
     }
+
+    /// Remove a variable from an explicit namespace, such as the one designated by `global::`
+    pub fn remove_from_namespace<Q: ?Sized>(&mut self, name: &Q, namespace: Namespace) -> Option<V>
+    where
+        K: Borrow<Q>,
+        Q: Hash + Eq,
+    {
+        let index = self.index_in_namespace(name, namespace)?;
+        self.scopes[index].remove(name)
+    }
 }
@@ -1,7 +1,8 @@
+use super::{fuzzy, keys::ViModeTracker, InteractiveShell};
 use auto_enums::auto_enum;
 use glob::{glob_with, MatchOptions};
-use ion_shell::{expansion::Expander, Shell};
-use liner::{Completer, CursorPosition, Event, EventKind};
+use ion_shell::{expansion::Expander, variables::Variables, Shell, Value};
+use liner::{Completer, CursorPosition, Event, EventKind, KeyBindings};
 use std::{
     env, iter,
     path::{Path, PathBuf},
@@ -9,8 +10,10 @@ use std::{
 };
 
 pub struct IonCompleter<'a, 'b> {
-    shell:      &'b Shell<'a>,
-    completion: CompletionType,
+    interactive: &'b InteractiveShell<'a>,
+    completion:  CompletionType,
+    /// The vi mode, followed to show it in the prompt with the vi keybindings
+    vi_mode:     Option<ViModeTracker>,
 }
 
 /// Unescape filenames for the completer so that special characters will be properly shown.
@@ -61,15 +64,20 @@ enum CompletionType {
 }
 
 impl<'a, 'b> IonCompleter<'a, 'b> {
-    pub fn new(shell: &'b Shell<'a>) -> Self {
-        IonCompleter { shell, completion: CompletionType::Nothing }
+    pub fn new(interactive: &'b InteractiveShell<'a>, key_bindings: KeyBindings) -> Self {
+        let vi_mode = match key_bindings {
+            KeyBindings::Vi => Some(ViModeTracker::default()),
+            KeyBindings::Emacs => None,
+        };
+        IonCompleter { interactive, completion: CompletionType::Nothing, vi_mode }
     }
 }
 
 impl<'a, 'b> Completer for IonCompleter<'a, 'b> {
     fn completions(&mut self, start: &str) -> Vec<String> {
-        let mut completions = IonFileCompleter::new(None, &self.shell).completions(start);
-        let vars = self.shell.variables();
+        let shell = self.interactive.shell.borrow();
+        let mut completions = IonFileCompleter::new(None, &shell).completions(start);
+        let vars = shell.variables();
 
         match self.completion {
             CompletionType::VariableAndFiles => {
@@ -81,15 +89,7 @@ impl<'a, 'b> Completer for IonCompleter<'a, 'b> {
                     completions.extend(vars.string_vars().map(|(s, _)| format!("${}", s)));
                     completions.extend(vars.arrays().map(|(s, _)| format!("@{}", s)));
                 } else if start.starts_with('$') {
-                    completions.extend(
-                        // Add the list of available variables to the completer's
-                        // definitions. TODO: We should make
-                        // it free to do String->SmallString
-                        //       and mostly free to go back (free if allocated)
-                        vars.string_vars()
-                            .filter(|(s, _)| s.starts_with(&start[1..]))
-                            .map(|(s, _)| format!("${}", &s)),
-                    );
+                    completions.extend(variable_completions(vars, start));
                 } else if start.starts_with('@') {
                     completions.extend(
                         vars.arrays()
@@ -103,19 +103,7 @@ impl<'a, 'b> Completer for IonCompleter<'a, 'b> {
                 // Creates a list of definitions from the shell environment that
                 // will be used
                 // in the creation of a custom completer.
-                completions.extend(
-                    self.shell
-                        .builtins()
-                        .keys()
-                        // Add built-in commands to the completer's definitions.
-                        .map(ToString::to_string)
-                        // Add the aliases to the completer's definitions.
-                        .chain(vars.aliases().map(|(key, _)| key.to_string()))
-                        // Add the list of available functions to the completer's
-                        // definitions.
-                        .chain(vars.functions().map(|(key, _)| key.to_string()))
-                        .filter(|s| s.starts_with(start)),
-                );
+                completions.extend(command_completions(&shell, start));
                 // Creates completers containing definitions from all directories
                 // listed
                 // in the environment's **$PATH** variable.
@@ -129,11 +117,11 @@ impl<'a, 'b> Completer for IonCompleter<'a, 'b> {
                             } else {
                                 s
                             };
-                            IonFileCompleter::new(Some(s), &self.shell)
+                            IonFileCompleter::new(Some(s), &shell)
                         })
                         .collect()
                 } else {
-                    vec![IonFileCompleter::new(Some("/bin/".into()), &self.shell)]
+                    vec![IonFileCompleter::new(Some("/bin/".into()), &shell)]
                 };
                 // Merge the collected definitions with the file path definitions.
                 completions.extend(MultiCompleter::new(file_completers).completions(start));
@@ -145,7 +133,23 @@ impl<'a, 'b> Completer for IonCompleter<'a, 'b> {
     }
 
     fn on_event<W: std::io::Write>(&mut self, event: Event<'_, '_, W>) {
-        if let EventKind::BeforeComplete = event.kind {
+        let key_map = self.interactive.key_map.borrow();
+        if let Some(binding) = match event.kind {
+            EventKind::BeforeKey(key) => key_map.get(key),
+            _ => None,
+        } {
+            if let Err(err) = binding.apply(event.editor) {
+                eprintln!("\nion: key binding: {}", err);
+            }
+            let _ = event.editor.display();
+        } else if let EventKind::AfterKey(key) = event.kind {
+            if let Some(ref mut vi_mode) = self.vi_mode {
+                if vi_mode.after_key(key) {
+                    event.editor.set_prompt(self.interactive.vi_prompt(vi_mode.mode()));
+                    let _ = event.editor.display();
+                }
+            }
+        } else if let EventKind::BeforeComplete = event.kind {
             let (words, pos) = event.editor.get_words_and_cursor_position();
             self.completion = match pos {
                 _ if words.is_empty() => CompletionType::Nothing,
@@ -177,6 +181,59 @@ impl<'a, 'b> Completer for IonCompleter<'a, 'b> {
     }
 }
 
+/// Completes the name of a string variable or environment variable starting with `$`. With
+/// the `${` form, the closing brace is added to the completions.
+fn variable_completions(vars: &Variables, start: &str) -> Vec<String> {
+    let (prefix, braced) = match start.strip_prefix("${") {
+        Some(prefix) => (prefix, true),
+        None => (&start[1..], false),
+    };
+    let mut names: Vec<String> = vars
+        .string_vars()
+        .map(|(name, _)| name.to_string())
+        .chain(env::vars().map(|(name, _)| name))
+        .filter(|name| name.starts_with(prefix))
+        .collect();
+    names.sort();
+    names.dedup();
+    names
+        .into_iter()
+        .map(|name| if braced { format!("${{{}}}", name) } else { format!("${}", name) })
+        .collect()
+}
+
+/// Indicates if command names should be completed fuzzily, as enabled by setting
+/// COMPLETION_FUZZY to 1, rather than by prefix
+fn fuzzy_enabled(vars: &Variables) -> bool {
+    vars.get_str("COMPLETION_FUZZY").map_or(false, |fuzzy| fuzzy == "1")
+}
+
+/// Completes the name of a builtin, alias or function. Names are only offered once, and only
+/// if they are not shadowed by another kind of variable in an inner scope. With fuzzy matching,
+/// the best matches come first.
+fn command_completions(shell: &Shell<'_>, start: &str) -> Vec<String> {
+    let vars = shell.variables();
+    let defined = vars
+        .aliases()
+        .map(|(name, _)| name)
+        .chain(vars.functions().map(|(name, _)| name))
+        .filter(|name| match vars.get(name) {
+            Some(Value::Alias(_)) | Some(Value::Function(_)) => true,
+            _ => false,
+        })
+        .map(ToString::to_string);
+    let mut names: Vec<String> =
+        shell.builtins().keys().map(ToString::to_string).chain(defined).collect();
+    names.sort();
+    names.dedup();
+    if fuzzy_enabled(vars) {
+        fuzzy::rank(names.iter().map(String::as_str), start).into_iter().map(String::from).collect()
+    } else {
+        names.retain(|name| name.starts_with(start));
+        names
+    }
+}
+
 /// Performs escaping to an inner `FilenameCompleter` to enable a handful of special cases
 /// needed by the shell, such as expanding '~' to a home directory, or adding a backslash
 /// when a special character is contained within an expanded filename.
@@ -230,32 +287,24 @@ impl<'a, 'b> Completer for IonFileCompleter<'a, 'b> {
                 completions.collect()
             };
         }
-        // We can do that by obtaining the index position where the tilde character
-        // ends. We don't search with `~` because we also want to
-        // handle other tilde variants.
-        let t_index = start.find('/').unwrap_or(1);
-        // `tilde` is the tilde pattern, and `search` is the pattern that follows.
+        // The tilde pattern ends where the shell stops expanding it. We don't search with `~`
+        // because we also want to handle other tilde variants, such as `~user` or `~+1`.
+        let t_index = start[1..].find(|c| c == '/' || c == '$').map_or(start.len(), |i| i + 1);
+        // `tilde` is the tilde pattern, and `search` is the pattern that follows, which was
+        // appended as-is to the directory the tilde pattern expanded to.
         let (tilde, search) = start.split_at(t_index);
+        let directory = match expanded.strip_suffix(search) {
+            Some(directory) => escape(directory),
+            None => return Vec::new(),
+        };
 
-        if search.len() < 2 {
-            // If the length of the search pattern is less than 2, the search pattern is
-            // empty, and thus the completions actually contain files and directories in
-            // the home directory.
-
-            // The tilde pattern will actually be our `start` command in itself,
-            // and the completed form will be all of the characters beyond the length of
-            // the expanded form of the tilde pattern.
-            completions.map(|completion| [start, &completion[expanded.len()..]].concat()).collect()
-        // To save processing time, we should get obtain the index position where our
-        // search pattern begins, and re-use that index to slice the completions so
-        // that we may re-add the tilde character with the completion that follows.
-        } else if let Some(e_index) = expanded.rfind(search) {
-            // And then we will need to take those completions and remove the expanded form
-            // of the tilde pattern and replace it with that pattern yet again.
-            completions.map(|completion| [tilde, &completion[e_index..]].concat()).collect()
-        } else {
-            Vec::new()
-        }
+        // Remove the expanded form of the tilde pattern from the completions and replace it
+        // with that pattern yet again, dropping the matches outside of that directory.
+        completions
+            .filter_map(|completion| {
+                completion.strip_prefix(directory.as_str()).map(|rest| [tilde, rest].concat())
+            })
+            .collect()
     }
 }
 
@@ -338,6 +387,63 @@ where
 #[cfg(test)]
 mod tests {
     use super::*;
+    use ion_shell::{
+        flow_control::{Function, Statement},
+        types,
+    };
+    use std::rc::Rc;
+
+    #[test]
+    fn variable_completion() {
+        let mut shell = Shell::default();
+        shell.variables_mut().set("PROMPT_SUFFIX", "# ");
+        let completions = variable_completions(shell.variables(), "$PROMPT");
+        assert!(completions.contains(&"$PROMPT".to_string()));
+        assert!(completions.contains(&"$PROMPT2".to_string()));
+        assert!(completions.contains(&"$PROMPT_SUFFIX".to_string()));
+        assert_eq!(completions.iter().filter(|name| *name == "$PROMPT").count(), 1);
+        assert!(completions.iter().all(|name| name.starts_with("$PROMPT")));
+
+        let completions = variable_completions(shell.variables(), "$P");
+        assert!(completions.contains(&"$PID".to_string()));
+        assert!(completions.contains(&"$PROMPT".to_string()));
+
+        shell.variables_mut().set("ION_COMPLETION_TEST", "value");
+        let completions = variable_completions(shell.variables(), "${ION_COMPLETION_");
+        assert_eq!(completions, vec!["${ION_COMPLETION_TEST}"]);
+    }
+
+    #[test]
+    fn alias_and_function_completion() {
+        let mut shell = Shell::default();
+        let function = |name: &str| {
+            Value::Function(Rc::new(Function::new(
+                None,
+                name.into(),
+                Vec::new(),
+                vec![Statement::End],
+            )))
+        };
+        shell.variables_mut().set("greet_alias", types::Alias("echo hello".into()));
+        shell.variables_mut().set("greet_fn", function("greet_fn"));
+        assert_eq!(command_completions(&shell, "greet_"), vec!["greet_alias", "greet_fn"]);
+
+        shell.variables_mut().new_scope(false);
+        shell.variables_mut().set_typed("greet_fn", function("greet_fn"));
+        shell.variables_mut().set_typed("greet_alias", Value::Str("not an alias".into()));
+        assert_eq!(command_completions(&shell, "greet_"), vec!["greet_fn"]);
+    }
+
+    #[test]
+    fn fuzzy_command_completion() {
+        let mut shell = Shell::default();
+        shell.variables_mut().set("graph_config", types::Alias("echo graph".into()));
+        shell.variables_mut().set("gc", types::Alias("git gc".into()));
+        assert!(!command_completions(&shell, "gc").contains(&"graph_config".to_string()));
+
+        shell.variables_mut().set("COMPLETION_FUZZY", "1");
+        assert_eq!(command_completions(&shell, "gc")[..2], ["gc", "graph_config"]);
+    }
 
     #[test]
     fn filename_completion() {
@@ -350,4 +456,25 @@ mod tests {
         }
         assert_eq!(completer.completions("tes/fil"), vec!["testing/file_with_text"]);
     }
+
+    #[test]
+    fn tilde_completion() {
+        let shell = Shell::default();
+        let home = shell.tilde("~").unwrap();
+        let mut completer = IonFileCompleter::new(None, &shell);
+        let entries = completer.completions(&format!("{}/", home));
+        let expected: Vec<_> = entries
+            .iter()
+            .filter_map(|entry| entry.strip_prefix(escape(&home).as_str()))
+            .map(|entry| format!("~{}", entry))
+            .collect();
+        assert_eq!(completer.completions("~/"), expected);
+
+        if let Some(entry) = expected.iter().find(|entry| !entry.contains('\\')) {
+            let partial: String = entry.chars().take(3).collect();
+            let completions = completer.completions(&partial);
+            assert!(completions.contains(entry));
+            assert!(completions.iter().all(|completion| completion.starts_with(&partial)));
+        }
+    }
 }
@@ -1,30 +1,71 @@
-use super::lexer::{DesignatorLexer, DesignatorToken};
+use super::{
+    history,
+    lexer::{DesignatorLexer, DesignatorToken},
+};
 use ion_shell::parser::lexers::ArgumentSplitter;
 use liner::Context;
 use std::{borrow::Cow, str};
 
 pub fn expand_designators<'a>(context: &Context, cmd: &'a str) -> Cow<'a, str> {
-    if let Some(buffer) = context.history.buffers.back() {
-        let buffer = buffer.as_bytes();
-        let buffer = unsafe { str::from_utf8_unchecked(&buffer) };
-        let mut output = String::with_capacity(cmd.len());
-        for token in DesignatorLexer::new(cmd.as_bytes()) {
-            match token {
-                DesignatorToken::Text(text) => output.push_str(text),
-                DesignatorToken::Designator(text) => match text {
-                    "!!" => output.push_str(buffer),
-                    "!$" => output.push_str(last_arg(buffer)),
-                    "!0" => output.push_str(command(buffer)),
-                    "!^" => output.push_str(first_arg(buffer)),
-                    "!*" => output.push_str(&args(buffer)),
-                    _ => output.push_str(text),
-                },
+    if !cmd.contains('!') {
+        return Cow::Borrowed(cmd);
+    }
+    // Timestamps and summaries are recorded as comments in the history
+    let history: Vec<String> = context
+        .history
+        .buffers
+        .iter()
+        .map(|entry| history::decode_entry(&entry.to_string()).into_owned())
+        .filter(|entry| !entry.starts_with('#'))
+        .collect();
+    expand_with_history(&history, cmd)
+}
+
+/// Expands the designators of `cmd` from the commands of `history`, the most recent one being
+/// the last. Designators which refer to no command are kept as they are, with a warning.
+fn expand_with_history<'a, S: AsRef<str>>(history: &[S], cmd: &'a str) -> Cow<'a, str> {
+    let previous = history.last().map(AsRef::as_ref);
+    let mut output = String::with_capacity(cmd.len());
+    for token in DesignatorLexer::new(cmd.as_bytes()) {
+        match token {
+            DesignatorToken::Text(text) => output.push_str(text),
+            DesignatorToken::Designator(text) => {
+                let expanded = match text {
+                    "!!" => previous.map(Cow::Borrowed),
+                    "!$" => previous.map(|buffer| Cow::Borrowed(last_arg(buffer))),
+                    "!0" => previous.map(|buffer| Cow::Borrowed(command(buffer))),
+                    "!^" => previous.map(|buffer| Cow::Borrowed(first_arg(buffer))),
+                    "!*" => previous.map(|buffer| Cow::Borrowed(args(buffer))),
+                    _ => match text[1..].parse::<usize>() {
+                        Ok(number) => numbered(history, number).map(Cow::Borrowed),
+                        Err(_) if text[1..].starts_with(char::is_alphabetic) => {
+                            with_prefix(history, &text[1..]).map(Cow::Borrowed)
+                        }
+                        // Not a designator, such as `!=`
+                        Err(_) => Some(Cow::Borrowed(text)),
+                    },
+                };
+                match expanded {
+                    Some(expanded) => output.push_str(&expanded),
+                    None => {
+                        eprintln!("ion: {}: event not found", text);
+                        output.push_str(text);
+                    }
+                }
             }
         }
-        Cow::Owned(output)
-    } else {
-        Cow::Borrowed(cmd)
     }
+    Cow::Owned(output)
+}
+
+/// The command number `number` of the history, counting from 1
+fn numbered<S: AsRef<str>>(history: &[S], number: usize) -> Option<&str> {
+    number.checked_sub(1).and_then(|index| history.get(index)).map(AsRef::as_ref)
+}
+
+/// The most recent command of the history starting with `prefix`
+fn with_prefix<'a, S: AsRef<str>>(history: &'a [S], prefix: &str) -> Option<&'a str> {
+    history.iter().rev().map(AsRef::as_ref).find(|command| command.starts_with(prefix))
 }
 
 fn command(text: &str) -> &str { ArgumentSplitter::new(text).next().unwrap_or(text) }
@@ -51,3 +92,46 @@ fn args(text: &str) -> &str {
 fn first_arg(text: &str) -> &str { ArgumentSplitter::new(text).nth(1).unwrap_or(text) }
 
 fn last_arg(text: &str) -> &str { ArgumentSplitter::new(text).last().unwrap_or(text) }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const HISTORY: &[&str] = &["ls -la /tmp", "git status", "echo hello world"];
+
+    fn expand(cmd: &str) -> String { expand_with_history(HISTORY, cmd).into_owned() }
+
+    #[test]
+    fn previous_command() {
+        assert_eq!(expand("sudo !!"), "sudo echo hello world");
+        assert_eq!(expand("cat !$"), "cat world");
+        assert_eq!(expand("!0 again"), "echo again");
+        assert_eq!(expand("printf !*"), "printf hello world");
+    }
+
+    #[test]
+    fn numbered_command() {
+        assert_eq!(expand("!1"), "ls -la /tmp");
+        assert_eq!(expand("!2 --short"), "git status --short");
+        assert_eq!(expand("!9"), "!9");
+    }
+
+    #[test]
+    fn command_with_prefix() {
+        assert_eq!(expand("!gi"), "git status");
+        assert_eq!(expand("!l | wc"), "ls -la /tmp | wc");
+        assert_eq!(expand("!unknown"), "!unknown");
+        assert_eq!(expand("!gi;ls"), "git status;ls");
+        assert_eq!(expand("!gi|wc"), "git status|wc");
+        assert_eq!(expand("!gi&&x"), "git status&&x");
+        assert_eq!(expand("!gi>out"), "git status>out");
+        assert_eq!(expand("echo $(!ec)"), "echo $(echo hello world)");
+    }
+
+    #[test]
+    fn not_designators() {
+        assert_eq!(expand("test a != b"), "test a != b");
+        assert_eq!(expand("echo \"!!\""), "echo \"!!\"");
+        assert_eq!(expand_with_history::<&str>(&[], "sudo !!"), "sudo !!");
+    }
+}
@@ -0,0 +1,93 @@
+use std::cmp::Reverse;
+
+/// The score of each matched character
+const MATCH: i64 = 16;
+/// The bonus of a character matched right after the previous one
+const CONTIGUOUS: i64 = 16;
+/// The bonus of a character matched at the start of a word
+const BOUNDARY: i64 = 12;
+
+/// Indicates if the character at `index` starts a word, such as a path component or the upper
+/// case letter of a camel case name
+fn is_boundary(chars: &[char], index: usize) -> bool {
+    index == 0
+        || matches!(chars[index - 1], '/' | '_' | '-' | '.' | ' ')
+        || (chars[index - 1].is_lowercase() && chars[index].is_uppercase())
+}
+
+/// Score how well `candidate` matches `query`, ignoring the case of ASCII letters. The
+/// characters of the query must appear in order in the candidate, but not necessarily next to
+/// each other. Matches of contiguous characters and at the start of words score higher, while
+/// the characters skipped before and between the matches lower the score. Returns None if the
+/// candidate does not match.
+pub fn fuzzy_score(candidate: &str, query: &str) -> Option<u32> {
+    let chars: Vec<char> = candidate.chars().collect();
+    let query: Vec<char> = query.chars().map(|c| c.to_ascii_lowercase()).collect();
+    let first = match query.first() {
+        Some(&first) => first,
+        None => return Some(0),
+    };
+    let is_match = |index: usize, c: char| chars[index].to_ascii_lowercase() == c;
+    let bonus = |index: usize| MATCH + if is_boundary(&chars, index) { BOUNDARY } else { 0 };
+
+    // The best score of the query so far, with its last character matched at each index
+    let mut scores: Vec<Option<i64>> = (0..chars.len())
+        .map(|index| if is_match(index, first) { Some(bonus(index) - index as i64) } else { None })
+        .collect();
+    for &c in &query[1..] {
+        let mut next = vec![None; chars.len()];
+        // The best score of a previous match followed by a gap, plus the index of that match
+        let mut gapped: Option<i64> = None;
+        for index in 1..chars.len() {
+            if index >= 2 {
+                if let Some(score) = scores[index - 2] {
+                    let score = score + index as i64 - 2;
+                    gapped = Some(gapped.map_or(score, |best| best.max(score)));
+                }
+            }
+            if is_match(index, c) {
+                let contiguous = scores[index - 1].map(|score| score + CONTIGUOUS);
+                let gapped = gapped.map(|score| score - index as i64 + 1);
+                next[index] = contiguous.max(gapped).map(|score| score + bonus(index));
+            }
+        }
+        scores = next;
+    }
+    scores.into_iter().flatten().max().map(|score| score.max(0) as u32)
+}
+
+/// The candidates matching `query`, the best matches first. Candidates with the same score keep
+/// their order.
+pub fn rank<'a, I: IntoIterator<Item = &'a str>>(candidates: I, query: &str) -> Vec<&'a str> {
+    let mut scored: Vec<_> = candidates
+        .into_iter()
+        .filter_map(|candidate| Some((fuzzy_score(candidate, query)?, candidate)))
+        .collect();
+    scored.sort_by_key(|&(score, _)| Reverse(score));
+    scored.into_iter().map(|(_, candidate)| candidate).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn subsequences_match() {
+        assert!(fuzzy_score("src/main.rs", "smr").is_some());
+        assert!(fuzzy_score("src/main.rs", "MAIN").is_some());
+        assert_eq!(fuzzy_score("src/main.rs", "rsm"), None);
+        assert_eq!(fuzzy_score("", "a"), None);
+        assert_eq!(fuzzy_score("anything", ""), Some(0));
+    }
+
+    #[test]
+    fn contiguous_and_boundary_matches_rank_first() {
+        assert!(fuzzy_score("src/main.rs", "main") > fuzzy_score("assets/main.css", "main"));
+        assert!(fuzzy_score("git commit", "gc") > fuzzy_score("glance", "gc"));
+        assert!(fuzzy_score("history", "his") > fuzzy_score("hide_status", "his"));
+        assert_eq!(
+            rank(vec!["assets/main.css", "docs/domain.md", "src/main.rs", "README"], "main"),
+            vec!["src/main.rs", "assets/main.css", "docs/domain.md"]
+        );
+    }
+}
@@ -1,8 +1,16 @@
 use super::InteractiveShell;
-use ion_shell::{builtins::Status, Value};
+use ion_shell::{builtins::Status, sys::FileLock, variables::Variables, Value};
 
+use glob::Pattern;
+use liner::{Buffer, Context};
 use regex::Regex;
-use std::time::{SystemTime, UNIX_EPOCH};
+use std::{
+    borrow::Cow,
+    fs::{self, OpenOptions},
+    io::{self, Write},
+    path::{Path, PathBuf},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
 
 #[derive(Debug, Default)]
 pub struct IgnoreSetting {
@@ -11,6 +19,8 @@ pub struct IgnoreSetting {
     all:             bool,
     /// ignore commands with leading whitespace ("whitespace")
     whitespace:      bool,
+    /// ignore commands with a leading space, but not other whitespace ("ignorespace")
+    pub space:       bool,
     /// ignore commands with status code 127 ("no_such_command")
     no_such_command: bool,
     /// used if regexes are defined.
@@ -19,6 +29,330 @@ pub struct IgnoreSetting {
     duplicates:      bool,
     // Yes, a bad heap-based Vec, however unfortunately its not possible to store Regex'es in Array
     regexes:         Vec<Regex>,
+    /// glob patterns given as plain entries, such as "rm *"
+    globs:           Vec<Pattern>,
+}
+
+impl IgnoreSetting {
+    /// Parse the entries of HISTORY_IGNORE
+    fn new<T>(patterns: &[Value<T>]) -> Self {
+        let mut settings = Self::default();
+        // for convenience and to avoid typos
+        let regex_prefix = "regex:";
+        for pattern in patterns.iter() {
+            let pattern = format!("{}", pattern);
+            match pattern.as_ref() {
+                "all" => settings.all = true,
+                "no_such_command" => settings.no_such_command = true,
+                "whitespace" => settings.whitespace = true,
+                "ignorespace" => settings.space = true,
+                "duplicates" => settings.duplicates = true,
+                // The length check is there to just ignore empty regex definitions
+                _ if pattern.starts_with(regex_prefix) && pattern.len() > regex_prefix.len() => {
+                    settings.based_on_regex = true;
+                    let regex_string = &pattern[regex_prefix.len()..];
+                    // We save the compiled regexes, as compiling them can be  an expensive task
+                    if let Ok(regex) = Regex::new(regex_string) {
+                        settings.regexes.push(regex);
+                    }
+                }
+                // Any other entry is a glob matched against the whole command
+                _ if !pattern.is_empty() => {
+                    if let Ok(glob) = Pattern::new(&pattern) {
+                        settings.globs.push(glob);
+                    }
+                }
+                _ => continue,
+            }
+        }
+        settings
+    }
+
+    /// Returns true if the command is matched by one of the regexes or globs
+    fn matches(&self, command: &str) -> bool {
+        self.regexes.iter().any(|regex| regex.is_match(command))
+            || self.globs.iter().any(|glob| glob.matches(command))
+    }
+
+    /// Returns true if the command is hidden from the history by a leading space or whitespace, a
+    /// regex or a glob. "all" only stops recording the following commands
+    fn hides(&self, command: &str) -> bool {
+        !command.contains("HISTORY_IGNORE")
+            && (self.whitespace && command.starts_with(char::is_whitespace)
+                || self.space && command.starts_with(' ')
+                || self.matches(command))
+    }
+}
+
+/// Returns the entries of the history which are not hidden by `ignore`, such as commands loaded
+/// from the history file before HISTORY_IGNORE was changed. The timestamp recorded before a
+/// hidden command is left out with it
+pub fn visible_entries<'a, I>(history: I, ignore: &IgnoreSetting) -> Vec<&'a str>
+where
+    I: IntoIterator<Item = &'a str>,
+{
+    let mut visible: Vec<&str> = Vec::new();
+    for entry in history {
+        if ignore.hides(entry) {
+            if visible.last().map_or(false, |last| parse_timestamp_entry(last).is_some()) {
+                visible.pop();
+            }
+        } else {
+            visible.push(entry);
+        }
+    }
+    visible
+}
+
+/// The summary recorded after each command when RECORD_SUMMARY is enabled, unless
+/// RECORD_SUMMARY_FORMAT is set
+pub const DEFAULT_SUMMARY_FORMAT: &str = "#summary# elapsed real time: %s seconds";
+
+/// Formats the summary of `command`, which took `elapsed` to run, replacing `%s` with the
+/// seconds, `%m` with the milliseconds, `%c` with the command and `%%` with a `%` in `format`
+pub fn summary_entry(format: &str, elapsed: Duration, command: &str) -> String {
+    let mut summary = String::with_capacity(format.len());
+    let mut chars = format.chars();
+    while let Some(c) = chars.next() {
+        if c != '%' {
+            summary.push(c);
+            continue;
+        }
+        match chars.next() {
+            Some('s') => {
+                summary.push_str(&format!("{}.{:09}", elapsed.as_secs(), elapsed.subsec_nanos()))
+            }
+            Some('m') => summary.push_str(&elapsed.as_millis().to_string()),
+            Some('c') => summary.push_str(command),
+            Some('%') => summary.push('%'),
+            Some(other) => {
+                summary.push('%');
+                summary.push(other);
+            }
+            None => summary.push('%'),
+        }
+    }
+    summary
+}
+
+/// Formats `time` as the entry recorded before a command when HISTORY_TIMESTAMP is enabled, an
+/// ISO-8601 UTC date such as `#2020-02-01T00:00:00Z`
+pub fn timestamp_entry(time: SystemTime) -> String {
+    let secs = time.duration_since(UNIX_EPOCH).unwrap_or_default().as_secs();
+    // Convert the days since the epoch to a civil date, treating years as starting in March
+    let days = secs / 86_400 + 719_468;
+    let era = days / 146_097;
+    let day_of_era = days % 146_097;
+    let year_of_era =
+        (day_of_era - day_of_era / 1460 + day_of_era / 36_524 - day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let shifted_month = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * shifted_month + 2) / 5 + 1;
+    let month = if shifted_month < 10 { shifted_month + 3 } else { shifted_month - 9 };
+    let year = era * 400 + year_of_era + if month <= 2 { 1 } else { 0 };
+    format!(
+        "#{:04}-{:02}-{:02}T{:02}:{:02}:{:02}Z",
+        year,
+        month,
+        day,
+        secs % 86_400 / 3600,
+        secs % 3600 / 60,
+        secs % 60
+    )
+}
+
+/// Returns the ISO-8601 date of a timestamp entry. Entries written by older versions of Ion,
+/// which stored the seconds since the epoch, are converted.
+fn parse_timestamp_entry(entry: &str) -> Option<String> {
+    let stamp = entry.strip_prefix('#')?;
+    if !stamp.is_empty() && stamp.bytes().all(|b| b.is_ascii_digit()) {
+        let secs = stamp.parse().ok()?;
+        return Some(timestamp_entry(UNIX_EPOCH + Duration::from_secs(secs))[1..].to_string());
+    }
+    let is_iso = stamp.len() == 20
+        && stamp.bytes().enumerate().all(|(i, b)| match i {
+            4 | 7 => b == b'-',
+            10 => b == b'T',
+            13 | 16 => b == b':',
+            19 => b == b'Z',
+            _ => b.is_ascii_digit(),
+        });
+    if is_iso {
+        Some(stamp.to_string())
+    } else {
+        None
+    }
+}
+
+/// The newlines of multi-line commands are saved as this character, so that each entry of the
+/// history, and of the history file, stays on a single line
+const NEWLINE_MARKER: char = '\u{2424}';
+
+/// The entry of the history saving `command`
+pub fn encode_entry(command: &str) -> Cow<'_, str> {
+    if command.contains('\n') {
+        Cow::Owned(command.replace('\n', &NEWLINE_MARKER.to_string()))
+    } else {
+        Cow::Borrowed(command)
+    }
+}
+
+/// The command saved by an entry of the history
+pub fn decode_entry(entry: &str) -> Cow<'_, str> {
+    if entry.contains(NEWLINE_MARKER) {
+        Cow::Owned(entry.replace(NEWLINE_MARKER, "\n"))
+    } else {
+        Cow::Borrowed(entry)
+    }
+}
+
+/// Renders the history for the `history` builtin, showing the timestamp recorded before a
+/// command on the same line as the command, and the following lines of multi-line commands
+/// indented
+pub fn render_entries<S: AsRef<str>>(entries: &[S]) -> Vec<String> {
+    let mut rendered = Vec::with_capacity(entries.len());
+    let mut timestamp = None;
+    for entry in entries.iter().map(AsRef::as_ref) {
+        let entry = entry.replace(NEWLINE_MARKER, "\n    ");
+        if let Some(stamp) = parse_timestamp_entry(&entry) {
+            timestamp = Some(stamp);
+        } else if let Some(stamp) = timestamp.take() {
+            rendered.push(format!("{} {}", stamp, entry));
+        } else {
+            rendered.push(entry);
+        }
+    }
+    rendered
+}
+
+/// The entries added to the history by this session, in order, whatever is later removed from
+/// the history in memory, with how many of them are already in the history file
+#[derive(Debug, Default)]
+pub struct SessionEntries {
+    entries: Vec<String>,
+    /// The number of entries already written to the history file, such as with inc_append
+    flushed: usize,
+}
+
+impl SessionEntries {
+    /// Pushes `entry` to the history of `context`, and records it unless it was left out as a
+    /// duplicate of the previous entry
+    pub fn push(&mut self, context: &mut Context, entry: String) -> io::Result<()> {
+        let last = |context: &Context| context.history.buffers.back().map(ToString::to_string);
+        let before = (context.history.buffers.len(), last(context));
+        context.history.push(entry.as_str().into())?;
+        if (context.history.buffers.len(), last(context)) != before {
+            // The history file is appended on each push with inc_append
+            let flushed = context.history.inc_append;
+            self.record(entry, flushed);
+        }
+        Ok(())
+    }
+
+    /// Records `entry`, which is already in the history file if `flushed` is set
+    fn record(&mut self, entry: String, flushed: bool) {
+        self.entries.push(entry);
+        if flushed {
+            self.flushed = self.entries.len();
+        }
+    }
+
+    /// The entries which are not in the history file yet
+    pub fn unflushed(&self) -> &[String] { &self.entries[self.flushed..] }
+
+    /// Marks all the entries as written to the history file
+    pub fn flush(&mut self) { self.flushed = self.entries.len(); }
+}
+
+/// Merges the entries of this session which are not in the history file yet after the entries
+/// `saved` in the file, which may have been extended by other sessions since it was loaded. The
+/// entries are not compared, as other sessions may have run the same commands
+pub fn merge_history<S: AsRef<str>>(saved: &[S], unflushed: &[S]) -> Vec<String> {
+    saved.iter().chain(unflushed).map(|entry| entry.as_ref().to_string()).collect()
+}
+
+/// Removes the oldest entries beyond the `cap` most recent ones, returning them in order
+pub fn trim_history<T>(entries: &mut Vec<T>, cap: usize) -> Vec<T> {
+    let excess = entries.len().saturating_sub(cap);
+    entries.drain(..excess).collect()
+}
+
+/// Appends `entries` to the file at `path`, one per line
+fn append_entries(path: &Path, entries: &[String]) -> io::Result<()> {
+    let mut file = OpenOptions::new().append(true).create(true).open(path)?;
+    entries.iter().try_for_each(|entry| writeln!(file, "{}", entry))
+}
+
+/// Writes the history to `histfile`, keeping the entries saved meanwhile by other sessions
+/// sharing the file, and at most the `cap` most recent entries. If `rotate` is set, the entries
+/// beyond the cap are appended to the file of the same name followed by `.old`. While another
+/// session writes the file, the entries of this session which are missing from it are appended
+/// instead, and the file is trimmed by a later session
+pub fn commit_merged(
+    context: &mut Context,
+    session: &mut SessionEntries,
+    histfile: &Path,
+    cap: Option<usize>,
+    rotate: bool,
+) {
+    let lock = FileLock::try_lock(histfile, true);
+    let saved = match fs::read_to_string(histfile) {
+        Ok(saved) => saved.lines().map(String::from).collect::<Vec<_>>(),
+        Err(_) => {
+            context.history.commit_to_file();
+            return session.flush();
+        }
+    };
+    let mut merged = merge_history(&saved, session.unflushed());
+    let result = if let Ok(Some(_lock)) = lock {
+        let trimmed = cap.map(|cap| trim_history(&mut merged, cap)).unwrap_or_default();
+        context.history.buffers = merged.iter().map(|entry| Buffer::from(entry.as_str())).collect();
+        context.history.commit_to_file();
+        if rotate && !trimmed.is_empty() {
+            let mut old = histfile.as_os_str().to_owned();
+            old.push(".old");
+            append_entries(Path::new(&old), &trimmed)
+        } else {
+            Ok(())
+        }
+    } else {
+        append_entries(histfile, &merged[saved.len()..])
+    };
+    match result {
+        Ok(()) => session.flush(),
+        Err(err) => eprintln!("ion: could not save the history: {}", err),
+    }
+}
+
+/// Where and how the history is written, from the HISTFILE, HISTFILE_SIZE and HISTFILE_ROTATE
+/// variables
+#[derive(Debug, Clone, Default)]
+pub struct HistoryFile {
+    path:   Option<PathBuf>,
+    cap:    Option<usize>,
+    rotate: bool,
+}
+
+impl HistoryFile {
+    /// The settings of the history file in `variables`
+    pub fn new(variables: &Variables) -> Self {
+        Self {
+            path:   variables.get_str("HISTFILE").ok().map(|path| PathBuf::from(path.as_str())),
+            cap:    variables.get_str("HISTFILE_SIZE").ok().and_then(|size| size.parse().ok()),
+            rotate: variables.get_str("HISTFILE_ROTATE").map_or(false, |rotate| rotate == "1"),
+        }
+    }
+
+    /// Writes the history of the session, merged with the other sessions sharing HISTFILE
+    pub fn commit(&self, context: &mut Context, session: &mut SessionEntries) {
+        match self.path {
+            Some(ref histfile) => commit_merged(context, session, histfile, self.cap, self.rotate),
+            None => {
+                context.history.commit_to_file();
+                session.flush();
+            }
+        }
+    }
 }
 
 /// Contains all history-related functionality for the `Shell`.
@@ -28,59 +362,89 @@ impl<'a> InteractiveShell<'a> {
     pub fn ignore_patterns(&self) -> IgnoreSetting {
         if let Some(Value::Array(patterns)) = self.shell.borrow().variables().get("HISTORY_IGNORE")
         {
-            let mut settings = IgnoreSetting::default();
-            // for convenience and to avoid typos
-            let regex_prefix = "regex:";
-            for pattern in patterns.iter() {
-                let pattern = format!("{}", pattern);
-                match pattern.as_ref() {
-                    "all" => settings.all = true,
-                    "no_such_command" => settings.no_such_command = true,
-                    "whitespace" => settings.whitespace = true,
-                    "duplicates" => settings.duplicates = true,
-                    // The length check is there to just ignore empty regex definitions
-                    _ if pattern.starts_with(regex_prefix)
-                        && pattern.len() > regex_prefix.len() =>
-                    {
-                        settings.based_on_regex = true;
-                        let regex_string = &pattern[regex_prefix.len()..];
-                        // We save the compiled regexes, as compiling them can be  an expensive task
-                        if let Ok(regex) = Regex::new(regex_string) {
-                            settings.regexes.push(regex);
-                        }
-                    }
-                    _ => continue,
-                }
-            }
-
-            settings
+            IgnoreSetting::new(patterns)
         } else {
             panic!("HISTORY_IGNORE is not set!");
         }
     }
 
+    /// Removes the commands hidden by HISTORY_IGNORE from the history before a line is read, so
+    /// that the Ctrl-R search of liner, which searches the whole history, does not find them
+    pub fn hide_ignored_history(&self) {
+        let ignore = self.ignore_patterns();
+        let mut context = self.context.borrow_mut();
+        let entries: Vec<String> =
+            context.history.buffers.iter().map(ToString::to_string).collect();
+        let visible = visible_entries(entries.iter().map(String::as_str), &ignore);
+        if visible.len() != entries.len() {
+            context.history.buffers = visible.into_iter().map(Buffer::from).collect();
+        }
+    }
+
     /// Saves a command in the history, depending on @HISTORY_IGNORE. Should be called
     /// immediately after `on_command()`
     pub fn save_command_in_history(&self, command: &str) {
-        if self.should_save_command(command) {
+        // Re-read the size every time, so that changing it during the session takes effect
+        let size = self
+            .shell
+            .borrow()
+            .variables()
+            .get_str("HISTORY_SIZE")
+            .ok()
+            .and_then(|size| size.parse::<usize>().ok());
+        if size == Some(0) {
+            self.context.borrow_mut().history.buffers.clear();
+            return;
+        }
+
+        if self.should_save_command(command) && !self.deduplicate(command) {
             if self.shell.borrow().variables().get_str("HISTORY_TIMESTAMP").unwrap_or_default()
                 == "1"
             {
-                // Get current time stamp
-                let since_unix_epoch =
-                    SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs();
-                let cur_time_sys = ["#", &since_unix_epoch.to_owned().to_string()].concat();
-
                 // Push current time to history
-                if let Err(err) = self.context.borrow_mut().history.push(cur_time_sys.into()) {
+                let timestamp = timestamp_entry(SystemTime::now());
+                if let Err(err) = self.push_entry(timestamp) {
                     eprintln!("ion: {}", err)
                 }
             }
 
             // Push command itself to history
-            if let Err(err) = self.context.borrow_mut().history.push(command.into()) {
+            if let Err(err) = self.push_entry(command.to_string()) {
                 eprintln!("ion: {}", err);
             }
+
+            if let Some(size) = size {
+                let mut context = self.context.borrow_mut();
+                let excess = context.history.buffers.len().saturating_sub(size);
+                context.history.buffers.drain(..excess);
+            }
+        }
+    }
+
+    /// Pushes `entry` to the history, recording it as an entry of this session
+    fn push_entry(&self, entry: String) -> io::Result<()> {
+        self.new_entries.borrow_mut().push(&mut self.context.borrow_mut(), entry)
+    }
+
+    /// Applies the @HISTORY_DEDUP policy before `command` is saved: `consecutive` skips a
+    /// command identical to the previous one, and `all` removes the prior identical commands.
+    /// Returns true if the command should not be saved
+    fn deduplicate(&self, command: &str) -> bool {
+        let policy = self.shell.borrow().variables().get_str("HISTORY_DEDUP").unwrap_or_default();
+        let mut context = self.context.borrow_mut();
+        match policy.as_str() {
+            "consecutive" => context
+                .history
+                .buffers
+                .iter()
+                .map(ToString::to_string)
+                .rfind(|entry| !entry.starts_with('#'))
+                .map_or(false, |previous| previous == command),
+            "all" => {
+                context.history.remove_duplicates(command);
+                false
+            }
+            _ => false,
         }
     }
 
@@ -97,8 +461,12 @@ impl<'a> InteractiveShell<'a> {
         }
 
         // Here we allow to also ignore the setting of the local variable because we
-        // assume the user entered the leading whitespace on purpose.
-        if ignore.whitespace && command.chars().next().map_or(false, char::is_whitespace) {
+        // assume the user entered the leading whitespace on purpose. Blank commands are
+        // ignored as well.
+        if ignore.whitespace
+            && (command.trim().is_empty()
+                || command.chars().next().map_or(false, char::is_whitespace))
+        {
             return false;
         }
 
@@ -112,12 +480,10 @@ impl<'a> InteractiveShell<'a> {
             self.context.borrow_mut().history.remove_duplicates(command);
         }
 
-        // ignore command when regex is matched but only if it does not contain
+        // ignore command when a regex or glob is matched but only if it does not contain
         // "HISTORY_IGNORE", otherwise we would also ignore the command which
         // sets the variable, which could be annoying.
-        if !command.contains("HISTORY_IGNORE")
-            && ignore.regexes.iter().any(|regex| regex.is_match(command))
-        {
+        if !command.contains("HISTORY_IGNORE") && ignore.matches(command) {
             return false;
         }
 
@@ -126,3 +492,234 @@ impl<'a> InteractiveShell<'a> {
         true
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ion_shell::{
+        types::{array, Array},
+        Shell,
+    };
+
+    #[test]
+    fn glob_entries_ignore_matching_commands() {
+        let settings = IgnoreSetting::new::<()>(&array!["whitespace", "git *"]);
+        assert!(settings.whitespace);
+        assert!(settings.matches("git status"));
+        assert!(settings.matches("git commit -m 'message'"));
+        assert!(!settings.matches("ls git"));
+        assert!(!settings.matches("gitk"));
+    }
+
+    /// Save the commands with the given HISTORY_IGNORE, returning the resulting history
+    fn saved_history(ignore: &[&str], commands: &[&str]) -> Vec<String> {
+        let shell = InteractiveShell::new(Shell::default());
+        let ignore: Array<_> = ignore.iter().map(|entry| Value::Str((*entry).into())).collect();
+        shell.shell.borrow_mut().variables_mut().set("HISTORY_IGNORE", ignore);
+        for command in commands {
+            shell.save_command_in_history(command);
+        }
+        let context = shell.context.borrow();
+        context.history.buffers.iter().map(ToString::to_string).collect()
+    }
+
+    #[test]
+    fn whitespace_ignores_blank_and_indented_commands() {
+        assert_eq!(saved_history(&["whitespace"], &["ls", " secret", "", "   "]), vec!["ls"]);
+        assert_eq!(saved_history(&[], &["ls", " pwd"]), vec!["ls", " pwd"]);
+    }
+
+    #[test]
+    fn ignorespace_ignores_commands_starting_with_a_space() {
+        let shell = InteractiveShell::new(Shell::default());
+        shell.shell.borrow_mut().variables_mut().set("HISTORY_IGNORE", array!["ignorespace"]);
+        for command in &["ls", " secret", " /", "\tpwd", "/"] {
+            shell.save_command(command);
+        }
+        let history: Vec<_> =
+            shell.context.borrow().history.buffers.iter().map(ToString::to_string).collect();
+        assert_eq!(history, vec!["ls", "\tpwd", "/"]);
+    }
+
+    #[test]
+    fn duplicates_keeps_the_last_occurrence() {
+        assert_eq!(saved_history(&["duplicates"], &["ls", "ls", "pwd", "ls"]), vec!["pwd", "ls"]);
+        assert_eq!(saved_history(&[], &["ls", "ls"]), vec!["ls", "ls"]);
+    }
+
+    #[test]
+    fn no_such_command_ignores_missing_commands() {
+        let shell = InteractiveShell::new(Shell::default());
+        shell.shell.borrow_mut().variables_mut().set("HISTORY_IGNORE", array!["no_such_command"]);
+        shell.save_command_in_history("ls");
+        shell.shell.borrow_mut().set_previous_status(Status::NO_SUCH_COMMAND);
+        shell.save_command_in_history("sl");
+        let history: Vec<_> =
+            shell.context.borrow().history.buffers.iter().map(ToString::to_string).collect();
+        assert_eq!(history, vec!["ls"]);
+    }
+
+    #[test]
+    fn regex_entries_ignore_matching_commands() {
+        let history = saved_history(
+            &["regex:^export .*TOKEN"],
+            &["export GITHUB_TOKEN=abc", "echo TOKEN", "export PATH=/bin"],
+        );
+        assert_eq!(history, vec!["echo TOKEN", "export PATH=/bin"]);
+    }
+
+    #[test]
+    fn ignored_entries_are_not_searched() {
+        let history = ["#1580000000", "secret --token abc", "#1580000001", " hidden", "git push"];
+        let ignore = IgnoreSetting::new::<()>(&array!["whitespace", "secret *"]);
+        assert_eq!(visible_entries(history.iter().copied(), &ignore), vec!["git push"]);
+        let summary = "#summary# elapsed real time: 0.1 seconds";
+        let history = [summary, "#1580000000", "secret --token abc"];
+        assert_eq!(visible_entries(history.iter().copied(), &ignore), vec![summary]);
+
+        let ignore = IgnoreSetting::new::<()>(&array!["all"]);
+        assert_eq!(visible_entries(history.iter().copied(), &ignore), history);
+        let ignore = IgnoreSetting::new::<()>(&array!["regex:^git"]);
+        assert_eq!(
+            visible_entries(["git push", "let HISTORY_IGNORE = [git]"].iter().copied(), &ignore),
+            vec!["let HISTORY_IGNORE = [git]"]
+        );
+    }
+
+    #[test]
+    fn dedup_policies() {
+        let commands = ["ls", "ls", "pwd", "ls", "testing", "testing/"];
+        for (policy, expected) in &[
+            ("none", vec!["ls", "ls", "pwd", "ls", "testing/", "testing/"]),
+            ("consecutive", vec!["ls", "pwd", "ls", "testing/"]),
+            ("all", vec!["pwd", "ls", "testing/"]),
+        ] {
+            let shell = InteractiveShell::new(Shell::default());
+            shell.shell.borrow_mut().variables_mut().set("HISTORY_IGNORE", array![]);
+            shell.shell.borrow_mut().variables_mut().set("HISTORY_DEDUP", *policy);
+            for command in &commands {
+                shell.save_command(command);
+            }
+            let history: Vec<_> =
+                shell.context.borrow().history.buffers.iter().map(ToString::to_string).collect();
+            assert_eq!(&history, expected, "policy {}", policy);
+        }
+    }
+
+    #[test]
+    fn history_size_caps_the_history() {
+        let shell = InteractiveShell::new(Shell::default());
+        shell.shell.borrow_mut().variables_mut().set("HISTORY_IGNORE", array!["whitespace"]);
+        shell.shell.borrow_mut().variables_mut().set("HISTORY_SIZE", "3");
+        for i in 0..5 {
+            shell.save_command_in_history(&format!("echo {}", i));
+        }
+        let history: Vec<_> =
+            shell.context.borrow().history.buffers.iter().map(ToString::to_string).collect();
+        assert_eq!(history, vec!["echo 2", "echo 3", "echo 4"]);
+
+        shell.shell.borrow_mut().variables_mut().set("HISTORY_SIZE", "0");
+        shell.save_command_in_history("echo 5");
+        assert!(shell.context.borrow().history.buffers.is_empty());
+    }
+
+    #[test]
+    fn summaries_follow_the_format() {
+        let elapsed = Duration::from_millis(1_250);
+        assert_eq!(
+            summary_entry(DEFAULT_SUMMARY_FORMAT, elapsed, "sleep 1"),
+            "#summary# elapsed real time: 1.250000000 seconds"
+        );
+        assert_eq!(
+            summary_entry("#%c took %mms (100%%) %x%", elapsed, "sleep 1"),
+            "#sleep 1 took 1250ms (100%) %x%"
+        );
+    }
+
+    #[test]
+    fn sessions_are_merged_without_losing_entries() {
+        let saved = ["ls", "other 1", "mine 1", "other 2"];
+        let mut session = SessionEntries::default();
+        session.record("mine 1".into(), true);
+        session.record("ls".into(), false);
+        session.record("mine 2".into(), false);
+        assert_eq!(session.unflushed(), ["ls", "mine 2"]);
+        assert_eq!(
+            merge_history(&saved, &["ls", "mine 2"]),
+            vec!["ls", "other 1", "mine 1", "other 2", "ls", "mine 2"]
+        );
+
+        session.flush();
+        assert!(session.unflushed().is_empty());
+        assert_eq!(merge_history(&saved, session.unflushed()), saved);
+    }
+
+    #[test]
+    fn history_file_settings_come_from_the_variables() {
+        let mut shell = Shell::default();
+        let file = HistoryFile::new(shell.variables());
+        assert_eq!((file.cap, file.rotate), (Some(100_000), false));
+
+        shell.variables_mut().set("HISTFILE", "/tmp/ion_history");
+        shell.variables_mut().set("HISTFILE_SIZE", "10");
+        shell.variables_mut().set("HISTFILE_ROTATE", "1");
+        let file = HistoryFile::new(shell.variables());
+        assert_eq!(file.path, Some(PathBuf::from("/tmp/ion_history")));
+        assert_eq!((file.cap, file.rotate), (Some(10), true));
+    }
+
+    #[test]
+    fn history_is_trimmed_to_the_most_recent_entries() {
+        let mut entries: Vec<_> = (1..=5).collect();
+        assert_eq!(trim_history(&mut entries, 3), vec![1, 2]);
+        assert_eq!(entries, vec![3, 4, 5]);
+        assert_eq!(trim_history(&mut entries, 10), Vec::<i32>::new());
+        assert_eq!(entries, vec![3, 4, 5]);
+        assert_eq!(trim_history(&mut entries, 0), vec![3, 4, 5]);
+        assert!(entries.is_empty());
+    }
+
+    #[test]
+    fn multiline_commands_are_saved_as_one_entry() {
+        let shell = InteractiveShell::new(Shell::default());
+        shell.shell.borrow_mut().variables_mut().set("HISTORY_IGNORE", array![]);
+        shell.save_command("echo 'first\nsecond'");
+        shell.save_command("ls");
+        let history: Vec<_> =
+            shell.context.borrow().history.buffers.iter().map(ToString::to_string).collect();
+        assert_eq!(history.len(), 2);
+        assert!(history.iter().all(|entry| !entry.contains('\n')));
+        assert_eq!(decode_entry(&history[0]), "echo 'first\nsecond'");
+        assert_eq!(decode_entry(&history[1]), "ls");
+        assert_eq!(render_entries(&history), vec!["echo 'first\n    second'", "ls"]);
+    }
+
+    #[test]
+    fn timestamps_are_formatted_as_iso_8601() {
+        let time = |secs| UNIX_EPOCH + Duration::from_secs(secs);
+        assert_eq!(timestamp_entry(time(0)), "#1970-01-01T00:00:00Z");
+        assert_eq!(timestamp_entry(time(1_580_515_200)), "#2020-02-01T00:00:00Z");
+        assert_eq!(timestamp_entry(time(951_825_845)), "#2000-02-29T12:04:05Z");
+    }
+
+    #[test]
+    fn history_renders_timestamps_when_present() {
+        let entries = [
+            "ls",
+            "#2020-02-01T00:00:00Z",
+            "cd /tmp",
+            "#1580515200",
+            "pwd",
+            "#summary# elapsed real time: 0.1 seconds",
+        ];
+        assert_eq!(
+            render_entries(&entries),
+            vec![
+                "ls",
+                "2020-02-01T00:00:00Z cd /tmp",
+                "2020-02-01T00:00:00Z pwd",
+                "#summary# elapsed real time: 0.1 seconds"
+            ]
+        );
+    }
+}
@@ -0,0 +1,396 @@
+use liner::{Editor, ViPromptMode};
+use std::{fmt, io, io::Write};
+use termion::event::Key;
+
+/// An editing action which can be bound to a key
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum EditAction {
+    BeginningOfLine,
+    EndOfLine,
+    BackwardChar,
+    ForwardChar,
+    KillLine,
+    DiscardLine,
+    ClearScreen,
+}
+
+impl EditAction {
+    const ALL: [Self; 7] = [
+        Self::BeginningOfLine,
+        Self::EndOfLine,
+        Self::BackwardChar,
+        Self::ForwardChar,
+        Self::KillLine,
+        Self::DiscardLine,
+        Self::ClearScreen,
+    ];
+
+    /// The name of the action, as given to `keybindings bind`
+    pub const fn name(self) -> &'static str {
+        match self {
+            Self::BeginningOfLine => "beginning-of-line",
+            Self::EndOfLine => "end-of-line",
+            Self::BackwardChar => "backward-char",
+            Self::ForwardChar => "forward-char",
+            Self::KillLine => "kill-line",
+            Self::DiscardLine => "discard-line",
+            Self::ClearScreen => "clear-screen",
+        }
+    }
+
+    /// Find an action by its name
+    pub fn from_name(name: &str) -> Option<Self> {
+        Self::ALL.iter().copied().find(|action| action.name() == name)
+    }
+
+    fn apply<W: Write>(self, editor: &mut Editor<'_, W>) -> io::Result<()> {
+        match self {
+            Self::BeginningOfLine => editor.move_cursor_to_start_of_line(),
+            Self::EndOfLine => editor.move_cursor_to_end_of_line(),
+            Self::BackwardChar => editor.move_cursor_left(1),
+            Self::ForwardChar => editor.move_cursor_right(1),
+            Self::KillLine => editor.delete_all_after_cursor(),
+            Self::DiscardLine => editor.delete_all_before_cursor(),
+            Self::ClearScreen => editor.clear(),
+        }
+    }
+}
+
+/// What a key is bound to
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Binding {
+    /// Perform an editing action
+    Edit(EditAction),
+    /// Replace the line with a shell command, ready to be run
+    Command(String),
+}
+
+impl Binding {
+    /// Parse the action given to `keybindings bind`: either the name of an editing action, or
+    /// `command` followed by a shell command
+    pub fn parse<S: AsRef<str>>(action: &[S]) -> Result<Self, String> {
+        let (name, args) = action.split_first().ok_or("an action must be given to bind a key")?;
+        match (name.as_ref(), args) {
+            ("command", []) => Err("a command must be given to bind a key to a command".into()),
+            ("command", args) => {
+                let args: Vec<_> = args.iter().map(AsRef::as_ref).collect();
+                Ok(Self::Command(args.join(" ")))
+            }
+            (name, args) => {
+                let action = EditAction::from_name(name).ok_or_else(|| {
+                    let names: Vec<_> =
+                        EditAction::ALL.iter().map(|action| action.name()).collect();
+                    format!(
+                        "unknown action `{}`. Choices are {} and command <command>",
+                        name,
+                        names.join(", ")
+                    )
+                })?;
+                if args.is_empty() {
+                    Ok(Self::Edit(action))
+                } else {
+                    Err(format!("the {} action takes no argument", name))
+                }
+            }
+        }
+    }
+
+    /// Perform the action on the line being edited
+    pub fn apply<W: Write>(&self, editor: &mut Editor<'_, W>) -> io::Result<()> {
+        match self {
+            Self::Edit(action) => action.apply(editor),
+            Self::Command(command) => {
+                editor.move_cursor_to_end_of_line()?;
+                editor.delete_all_before_cursor()?;
+                editor.insert_str_after_cursor(command)
+            }
+        }
+    }
+}
+
+impl fmt::Display for Binding {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Edit(action) => write!(f, "{}", action.name()),
+            Self::Command(command) => write!(f, "command {}", command),
+        }
+    }
+}
+
+/// The keys bound by the user, in the order they were bound
+#[derive(Debug, Default)]
+pub struct KeyMap(Vec<(Key, Binding)>);
+
+impl KeyMap {
+    /// Bind a key, replacing its previous binding
+    pub fn bind(&mut self, key: Key, binding: Binding) {
+        match self.0.iter_mut().find(|(bound, _)| *bound == key) {
+            Some((_, previous)) => *previous = binding,
+            None => self.0.push((key, binding)),
+        }
+    }
+
+    /// Get the binding of a key
+    pub fn get(&self, key: Key) -> Option<&Binding> {
+        self.0.iter().find(|(bound, _)| *bound == key).map(|(_, binding)| binding)
+    }
+
+    /// The bound keys, with their key specification
+    pub fn iter(&self) -> impl Iterator<Item = (String, &Binding)> {
+        self.0.iter().map(|(key, binding)| (key_spec(*key), binding))
+    }
+}
+
+/// Parse a key specification such as `C-x` (control), `M-f` (alt), `F5`, a single character or
+/// the name of a special key like `up` or `delete`
+pub fn parse_key(spec: &str) -> Result<Key, String> {
+    let single = |rest: &str| {
+        let mut chars = rest.chars();
+        match (chars.next(), chars.next()) {
+            (Some(c), None) => Some(c),
+            _ => None,
+        }
+    };
+    let key = match spec {
+        "up" => Some(Key::Up),
+        "down" => Some(Key::Down),
+        "left" => Some(Key::Left),
+        "right" => Some(Key::Right),
+        "home" => Some(Key::Home),
+        "end" => Some(Key::End),
+        "pageup" => Some(Key::PageUp),
+        "pagedown" => Some(Key::PageDown),
+        "backspace" => Some(Key::Backspace),
+        "delete" => Some(Key::Delete),
+        "tab" => Some(Key::Char('\t')),
+        "esc" => Some(Key::Esc),
+        _ => {
+            if let Some(rest) = spec.strip_prefix("C-") {
+                single(rest).map(|c| Key::Ctrl(c.to_ascii_lowercase()))
+            } else if let Some(rest) = spec.strip_prefix("M-") {
+                single(rest).map(Key::Alt)
+            } else if let Some(number) = spec.strip_prefix('F') {
+                number.parse().ok().filter(|n| (1..=12).contains(n)).map(Key::F)
+            } else {
+                single(spec).map(Key::Char)
+            }
+        }
+    };
+    key.ok_or_else(|| {
+        format!(
+            "invalid key `{}`: expected a character, C-<char>, M-<char>, F1 to F12 or a key name",
+            spec
+        )
+    })
+}
+
+/// The specification of a key, as parsed by `parse_key`
+pub fn key_spec(key: Key) -> String {
+    match key {
+        Key::Up => "up".into(),
+        Key::Down => "down".into(),
+        Key::Left => "left".into(),
+        Key::Right => "right".into(),
+        Key::Home => "home".into(),
+        Key::End => "end".into(),
+        Key::PageUp => "pageup".into(),
+        Key::PageDown => "pagedown".into(),
+        Key::Backspace => "backspace".into(),
+        Key::Delete => "delete".into(),
+        Key::Char('\t') => "tab".into(),
+        Key::Esc => "esc".into(),
+        Key::Ctrl(c) => format!("C-{}", c),
+        Key::Alt(c) => format!("M-{}", c),
+        Key::F(n) => format!("F{}", n),
+        Key::Char(c) => c.to_string(),
+        _ => format!("{:?}", key),
+    }
+}
+
+/// The keys handled by liner with the emacs or vi keybindings. Liner gives no way to take over a
+/// key, so it would still handle them after their binding is applied
+const LINER_KEYS: &[Key] = &[
+    Key::Up,
+    Key::Down,
+    Key::Left,
+    Key::Right,
+    Key::Home,
+    Key::End,
+    Key::Backspace,
+    Key::Delete,
+    Key::Esc,
+    Key::Ctrl('a'),
+    Key::Ctrl('b'),
+    Key::Ctrl('c'),
+    Key::Ctrl('d'),
+    Key::Ctrl('e'),
+    Key::Ctrl('f'),
+    Key::Ctrl('h'),
+    Key::Ctrl('k'),
+    Key::Ctrl('l'),
+    Key::Ctrl('n'),
+    Key::Ctrl('p'),
+    Key::Ctrl('r'),
+    Key::Ctrl('s'),
+    Key::Ctrl('u'),
+    Key::Ctrl('w'),
+    Key::Alt('b'),
+    Key::Alt('d'),
+    Key::Alt('f'),
+];
+
+/// Checks that `key` can be bound with `keybindings bind`. Characters would still be inserted,
+/// or read as vi commands, and the keys of liner would still be handled by liner
+pub fn bindable(key: Key) -> Result<Key, String> {
+    match key {
+        Key::Char(_) => Err(format!(
+            "`{}` is read by the line editor as a character, and cannot be bound",
+            key_spec(key)
+        )),
+        _ if LINER_KEYS.contains(&key) => Err(format!(
+            "`{}` is already bound by the line editor, and cannot be bound again",
+            key_spec(key)
+        )),
+        _ => Ok(key),
+    }
+}
+
+/// The vi mode of the line editor, followed from the keys it reads since liner does not report
+/// it. Each line starts in insert mode
+#[derive(Debug, Default)]
+pub struct ViModeTracker {
+    normal:  bool,
+    /// The command of the normal mode waiting for its next key
+    pending: Option<Pending>,
+}
+
+/// A command of the normal mode waiting for its next key, which may enter the insert mode
+#[derive(Clone, Copy, Debug)]
+enum Pending {
+    /// An operator such as `c` or `d` waiting for its motion
+    Operator { insert: bool },
+    /// A command such as `r` or `f` waiting for its character
+    Character { insert: bool },
+}
+
+impl ViModeTracker {
+    /// The current mode
+    pub fn mode(&self) -> ViPromptMode {
+        if self.normal {
+            ViPromptMode::Normal
+        } else {
+            ViPromptMode::Insert
+        }
+    }
+
+    /// Follow the mode after the editor read `key`. Returns true if the mode changed
+    pub fn after_key(&mut self, key: Key) -> bool {
+        let normal = self.normal;
+        match (self.pending.take(), key) {
+            (_, Key::Esc) => self.normal = true,
+            _ if !self.normal => (),
+            (Some(Pending::Operator { insert }), Key::Char(c)) if "fFtT".contains(c) => {
+                self.pending = Some(Pending::Character { insert })
+            }
+            // A count between the operator and its motion
+            (Some(Pending::Operator { insert }), Key::Char(c)) if ('1'..='9').contains(&c) => {
+                self.pending = Some(Pending::Operator { insert })
+            }
+            (Some(Pending::Operator { insert }), _) | (Some(Pending::Character { insert }), _) => {
+                self.normal = !insert
+            }
+            (None, Key::Char(c)) if "iaIAsSCR".contains(c) => self.normal = false,
+            (None, Key::Char(c)) if "cdy".contains(c) => {
+                self.pending = Some(Pending::Operator { insert: c == 'c' })
+            }
+            (None, Key::Char(c)) if "rfFtT".contains(c) => {
+                self.pending = Some(Pending::Character { insert: false })
+            }
+            (None, _) => (),
+        }
+        self.normal != normal
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn key_specs() {
+        assert_eq!(parse_key("C-x"), Ok(Key::Ctrl('x')));
+        assert_eq!(parse_key("C-X"), Ok(Key::Ctrl('x')));
+        assert_eq!(parse_key("M-f"), Ok(Key::Alt('f')));
+        assert_eq!(parse_key("F5"), Ok(Key::F(5)));
+        assert_eq!(parse_key("up"), Ok(Key::Up));
+        assert_eq!(parse_key("tab"), Ok(Key::Char('\t')));
+        assert_eq!(parse_key("q"), Ok(Key::Char('q')));
+        assert!(parse_key("C-").is_err());
+        assert!(parse_key("C-xy").is_err());
+        assert!(parse_key("F13").is_err());
+        assert!(parse_key("hyper-x").is_err());
+
+        for spec in &["C-x", "M-f", "F5", "up", "tab", "q"] {
+            assert_eq!(key_spec(parse_key(spec).unwrap()), *spec);
+        }
+    }
+
+    #[test]
+    fn keys_of_the_line_editor_cannot_be_bound() {
+        assert_eq!(bindable(Key::Ctrl('x')), Ok(Key::Ctrl('x')));
+        assert_eq!(bindable(Key::Alt('g')), Ok(Key::Alt('g')));
+        assert_eq!(bindable(Key::F(5)), Ok(Key::F(5)));
+        assert!(bindable(Key::Char('q')).is_err());
+        assert!(bindable(Key::Char('\t')).is_err());
+        assert!(bindable(Key::Ctrl('r')).is_err());
+        assert!(bindable(Key::Alt('f')).is_err());
+        assert!(bindable(Key::Up).is_err());
+    }
+
+    #[test]
+    fn bindings() {
+        assert_eq!(Binding::parse(&["end-of-line"]), Ok(Binding::Edit(EditAction::EndOfLine)));
+        assert_eq!(
+            Binding::parse(&["command", "git", "status"]),
+            Ok(Binding::Command("git status".into()))
+        );
+        assert!(Binding::parse(&["fly"]).is_err());
+        assert!(Binding::parse(&["command"]).is_err());
+        assert!(Binding::parse(&["end-of-line", "now"]).is_err());
+        assert!(Binding::parse::<&str>(&[]).is_err());
+
+        let mut map = KeyMap::default();
+        map.bind(Key::Ctrl('x'), Binding::Edit(EditAction::KillLine));
+        map.bind(Key::Alt('g'), Binding::Command("git status".into()));
+        map.bind(Key::Ctrl('x'), Binding::Edit(EditAction::DiscardLine));
+        assert_eq!(map.get(Key::Ctrl('x')), Some(&Binding::Edit(EditAction::DiscardLine)));
+        assert_eq!(map.get(Key::Ctrl('y')), None);
+        let listed: Vec<_> =
+            map.iter().map(|(key, binding)| format!("{} {}", key, binding)).collect();
+        assert_eq!(listed, vec!["C-x discard-line", "M-g command git status"]);
+    }
+
+    #[test]
+    fn vi_mode_is_followed() {
+        let mut tracker = ViModeTracker::default();
+        assert!(matches!(tracker.mode(), ViPromptMode::Insert));
+        assert!(!tracker.after_key(Key::Char('c')));
+        assert!(tracker.after_key(Key::Esc));
+        assert!(matches!(tracker.mode(), ViPromptMode::Normal));
+
+        // The characters given to the commands and the motions are not commands
+        for c in "fidta".chars() {
+            assert!(!tracker.after_key(Key::Char(c)));
+        }
+        assert!(!tracker.after_key(Key::Char('c')));
+        assert!(!tracker.after_key(Key::Esc));
+        assert!(matches!(tracker.mode(), ViPromptMode::Normal));
+
+        assert!(!tracker.after_key(Key::Char('c')));
+        assert!(!tracker.after_key(Key::Char('2')));
+        assert!(tracker.after_key(Key::Char('w')));
+        assert!(matches!(tracker.mode(), ViPromptMode::Insert));
+        assert!(tracker.after_key(Key::Esc));
+        assert!(tracker.after_key(Key::Char('A')));
+    }
+}
@@ -52,7 +52,16 @@ impl<'a> Iterator for DesignatorLexer<'a> {
                         return Some(DesignatorToken::Text(self.grab_and_shorten(id)));
                     }
                 }
-                b' ' | b'\t' | b'\'' | b'"' | b'a'..=b'z' | b'A'..=b'Z' if self.design => {
+                // Designators also end where the command or the word does, as in `!gi;ls`
+                b' ' | b'\t' | b'\'' | b'"' | b';' | b'|' | b'&' | b')' | b'<' | b'>'
+                    if self.design =>
+                {
+                    self.design = false;
+                    return Some(DesignatorToken::Designator(self.grab_and_shorten(id)));
+                }
+                // Letters right after `!` start a `!prefix` designator, which goes on until one of
+                // the characters above
+                b'a'..=b'z' | b'A'..=b'Z' if self.design && !self.data[1].is_ascii_alphabetic() => {
                     self.design = false;
                     return Some(DesignatorToken::Designator(self.grab_and_shorten(id)));
                 }
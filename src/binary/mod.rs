@@ -2,7 +2,9 @@
 pub mod builtins;
 mod completer;
 mod designators;
+mod fuzzy;
 mod history;
+mod keys;
 mod lexer;
 mod prompt;
 mod readln;
@@ -11,6 +13,7 @@ use ion_shell::{
     builtins::{man_pages, BuiltinFunction, Status},
     expansion::Expander,
     parser::Terminator,
+    sys::{self, FileLock, TerminalMode},
     types::{self, array},
     IonError, PipelineError, Shell, Signal, Value,
 };
@@ -21,8 +24,11 @@ use std::{
     fs::{self, OpenOptions},
     io::{self, Write},
     os::unix::io::{AsRawFd, IntoRawFd},
-    path::Path,
+    panic,
+    path::{Path, PathBuf},
     rc::Rc,
+    thread,
+    time::Duration,
 };
 use xdg::BaseDirectories;
 
@@ -37,17 +43,28 @@ FLAGS:
     -f, --fake-interactive    Use a fake interactive mode, where errors don't exit the shell
     -h, --help                Prints help information
     -i, --interactive         Force interactive mode
-    -n, --no-execute          Do not execute any commands, perform only syntax checking
+    -l, --login               Source the login profiles before the init file, and set LOGIN_SHELL to 1
+    -n, --no-execute          Do not execute any commands, only report the syntax errors with their position
     -x                        Print commands before execution
     -v, --version             Print the version, platform and revision of Ion then exit
 
 OPTIONS:
-    -c <command>             Evaluate given commands instead of reading from the commandline
+    -c <command>             Evaluate given commands instead of reading from the commandline. Can be
+                             repeated to run several commands in order
     -o <key-bindings>        Shortcut layout. Valid options: "vi", "emacs"
 
 ARGS:
     <args>...    Script arguments (@args). If the -c option is not specified, the first parameter is taken as a
-                 filename to execute"#;
+                 filename to execute
+
+LOGIN PROFILES:
+    A login shell sources /etc/ion/login, then $XDG_CONFIG_HOME/ion/login (~/.config/ion/login by default),
+    skipping the files which do not exist, before the init file of interactive shells
+
+PROMPT:
+    The prompt is expanded again after the terminal is resized, such as to align RPROMPT on the
+    new width. A line being edited keeps its prompt: the resize is taken into account by the next
+    prompt"#;
 
 pub(crate) const MAN_HISTORY: &str = r#"NAME
     history - print command history
@@ -68,28 +85,54 @@ OPTIONS:
 "#;
 
 pub struct InteractiveShell<'a> {
-    context:    Rc<RefCell<Context>>,
-    shell:      RefCell<Shell<'a>>,
-    terminated: Cell<bool>,
-    huponexit:  Rc<Cell<bool>>,
+    context:      Rc<RefCell<Context>>,
+    shell:        RefCell<Shell<'a>>,
+    terminated:   Cell<bool>,
+    huponexit:    Rc<Cell<bool>>,
+    /// The keys bound with `keybindings bind`
+    key_map:      Rc<RefCell<keys::KeyMap>>,
+    /// The last expanded prompt, with the state it was expanded in
+    prompt_cache: RefCell<Option<(prompt::PromptFingerprint, String)>>,
+    /// The number of commands executed, which invalidates the cached prompt
+    commands_run: Cell<usize>,
+    /// The command line being executed, for the summaries of RECORD_SUMMARY
+    last_command: Rc<RefCell<String>>,
+    /// The settings of the history file after the last command, to save the history on a panic
+    history_file: Rc<RefCell<history::HistoryFile>>,
+    /// The entries added to the history by this session, to append them to the history file
+    new_entries:  Rc<RefCell<history::SessionEntries>>,
 }
 
 impl<'a> InteractiveShell<'a> {
     const CONFIG_FILE_NAME: &'static str = "initrc";
+    const LOGIN_FILE_NAME: &'static str = "login";
+    const SYSTEM_LOGIN_FILE: &'static str = "/etc/ion/login";
+    const DIRSTACK_FILE_NAME: &'static str = "dirstack";
 
     pub fn new(shell: Shell<'a>) -> Self {
         let mut context = Context::new();
         context.word_divider_fn = Box::new(word_divide);
         InteractiveShell {
-            context:    Rc::new(RefCell::new(context)),
-            shell:      RefCell::new(shell),
-            terminated: Cell::new(true),
-            huponexit:  Rc::new(Cell::new(false)),
+            context:      Rc::new(RefCell::new(context)),
+            shell:        RefCell::new(shell),
+            terminated:   Cell::new(true),
+            huponexit:    Rc::new(Cell::new(false)),
+            key_map:      Rc::new(RefCell::new(keys::KeyMap::default())),
+            prompt_cache: RefCell::new(None),
+            commands_run: Cell::new(0),
+            last_command: Rc::new(RefCell::new(String::new())),
+            history_file: Rc::new(RefCell::new(history::HistoryFile::default())),
+            new_entries:  Rc::new(RefCell::new(history::SessionEntries::default())),
         }
     }
 
     /// Handles commands given by the REPL, and saves them to history.
     pub fn save_command(&self, cmd: &str) {
+        // Checked before a directory is given its trailing `/`
+        if cmd.starts_with(' ') && self.ignore_patterns().space {
+            return;
+        }
+        let cmd = &*history::encode_entry(cmd);
         if !cmd.ends_with('/')
             && self
                 .shell
@@ -106,18 +149,28 @@ impl<'a> InteractiveShell<'a> {
 
     pub fn add_callbacks(&self) {
         let context = self.context.clone();
+        let last_command = self.last_command.clone();
+        let new_entries = self.new_entries.clone();
         self.shell.borrow_mut().set_on_command(Some(Box::new(move |shell, elapsed| {
             // If `RECORD_SUMMARY` is set to "1" (True, Yes), then write a summary of the
             // pipline just executed to the the file and context histories. At the
             // moment, this means record how long it took.
             if Some("1".into()) == shell.variables().get_str("RECORD_SUMMARY").ok() {
-                let summary =
-                    format!("#summary# elapsed real time: {:.9} seconds", elapsed.as_secs_f32(),);
+                let format = shell.variables().get_str("RECORD_SUMMARY_FORMAT").ok();
+                let format = format.as_deref().unwrap_or(history::DEFAULT_SUMMARY_FORMAT);
+                let summary = history::summary_entry(format, elapsed, &last_command.borrow());
                 println!("{}", summary);
-                context.borrow_mut().history.push(summary.into()).unwrap_or_else(|err| {
+                let mut context = context.borrow_mut();
+                new_entries.borrow_mut().push(&mut context, summary).unwrap_or_else(|err| {
                     eprintln!("ion: history append: {}", err);
                 });
             }
+            // Report the commands taking longer than `REPORT_TIME` seconds, like zsh's REPORTTIME
+            let threshold = shell.variables().get_str("REPORT_TIME").ok();
+            if threshold.map_or(false, |threshold| exceeds_report_time(&threshold, elapsed)) {
+                let format = "ion: %c: %s seconds";
+                eprintln!("{}", history::summary_entry(format, elapsed, &last_command.borrow()));
+            }
         })));
     }
 
@@ -130,7 +183,11 @@ impl<'a> InteractiveShell<'a> {
     /// Liner.
     pub fn execute_interactive(self) -> ! {
         let context_bis = self.context.clone();
+        let new_entries_bis = self.new_entries.clone();
         let huponexit = self.huponexit.clone();
+        let dir_stack_file = BaseDirectories::with_prefix("ion")
+            .ok()
+            .and_then(|dirs| dirs.place_data_file(Self::DIRSTACK_FILE_NAME).ok());
         let prep_for_exit = &move |shell: &mut Shell<'_>| {
             // context will be sent a signal to commit all changes to the history file,
             // and waiting for the history thread in the background to finish.
@@ -138,7 +195,11 @@ impl<'a> InteractiveShell<'a> {
                 shell.resume_stopped();
                 shell.background_send(Signal::SIGHUP).expect("Failed to prepare for exit");
             }
-            context_bis.borrow_mut().history.commit_to_file();
+            history::HistoryFile::new(shell.variables())
+                .commit(&mut context_bis.borrow_mut(), &mut new_entries_bis.borrow_mut());
+            if let Some(file) = &dir_stack_file {
+                Self::save_dir_stack(shell, file);
+            }
         };
 
         let exit = self.shell.borrow().builtins().get("exit").unwrap();
@@ -187,7 +248,10 @@ impl<'a> InteractiveShell<'a> {
                     );
                 }
                 None => {
-                    print!("{}", context_bis.borrow().history.buffers.iter().format("\n"));
+                    let context = context_bis.borrow();
+                    let entries: Vec<_> =
+                        context.history.buffers.iter().map(ToString::to_string).collect();
+                    print!("{}", history::render_entries(&entries).iter().format("\n"));
                 }
             }
             Status::SUCCESS
@@ -200,23 +264,58 @@ impl<'a> InteractiveShell<'a> {
         };
 
         let context_bis = self.context.clone();
-        let keybindings = &move |args: &[types::Str], _shell: &mut Shell<'_>| -> Status {
+        let key_map = self.key_map.clone();
+        let keybindings = &move |args: &[types::Str], shell: &mut Shell<'_>| -> Status {
             match args.get(1).map(|s| s.as_str()) {
                 Some("vi") => {
                     context_bis.borrow_mut().key_bindings = KeyBindings::Vi;
+                    shell.variables_mut().set("VI_MODE", prompt::vi_mode(KeyBindings::Vi));
                     Status::SUCCESS
                 }
                 Some("emacs") => {
                     context_bis.borrow_mut().key_bindings = KeyBindings::Emacs;
+                    shell.variables_mut().set("VI_MODE", prompt::vi_mode(KeyBindings::Emacs));
+                    Status::SUCCESS
+                }
+                Some("bind") => {
+                    let key = match args.get(2) {
+                        Some(spec) => keys::parse_key(spec).and_then(keys::bindable),
+                        None => Err("a key must be given to bind it".into()),
+                    };
+                    match key.and_then(|key| Ok((key, keys::Binding::parse(&args[3..])?))) {
+                        Ok((key, binding)) => {
+                            key_map.borrow_mut().bind(key, binding);
+                            Status::SUCCESS
+                        }
+                        Err(why) => Status::error(format!("keybindings: {}", why)),
+                    }
+                }
+                Some("list") => {
+                    for (key, binding) in key_map.borrow().iter() {
+                        println!("{:<12}{}", key, binding);
+                    }
                     Status::SUCCESS
                 }
-                Some(_) => Status::error("Invalid keybindings. Choices are vi and emacs"),
+                Some(_) => Status::error(
+                    "Invalid keybindings. Choices are vi, emacs, bind <key> <action> and list",
+                ),
                 None => Status::error("keybindings need an argument"),
             }
         };
 
         // change the lifetime to allow adding local builtins
-        let InteractiveShell { context, shell, terminated, huponexit } = self;
+        let InteractiveShell {
+            context,
+            shell,
+            terminated,
+            huponexit,
+            key_map,
+            prompt_cache,
+            commands_run,
+            last_command,
+            history_file,
+            new_entries,
+        } = self;
         let mut shell = shell.into_inner();
         shell
             .builtins_mut()
@@ -226,16 +325,49 @@ impl<'a> InteractiveShell<'a> {
             .add("exec", exec, "Replace the shell with the given command.")
             .add("huponexit", set_huponexit, "Hangup the shell's background jobs on exit");
 
+        sys::watch_resizes();
         match BaseDirectories::with_prefix("ion") {
             Ok(project_dir) => {
+                if let Some(Value::Str(login)) = shell.variables().get("LOGIN_SHELL") {
+                    if login == "1" {
+                        Self::exec_login_files(&project_dir.get_config_home(), &mut shell);
+                    }
+                }
                 Self::exec_init_file(&project_dir, &mut shell);
+                Self::load_dir_stack(&project_dir, &mut shell);
                 Self::load_history(&project_dir, &mut shell, &mut context.borrow_mut());
             }
             Err(err) => eprintln!("ion: unable to get xdg base directory: {:?}", err),
         }
 
-        InteractiveShell { context, shell: RefCell::new(shell), terminated, huponexit }
-            .exec(prep_for_exit)
+        // A panicking builtin must not leave the terminal without echo, nor lose the history
+        history_file.replace(history::HistoryFile::new(shell.variables()));
+        let terminal = TerminalMode::save();
+        let context_bis = context.clone();
+        let history_file_bis = history_file.clone();
+        let new_entries_bis = new_entries.clone();
+        set_panic_hook(Box::new(move || {
+            terminal.restore();
+            if let (Ok(mut context), Ok(mut entries)) =
+                (context_bis.try_borrow_mut(), new_entries_bis.try_borrow_mut())
+            {
+                history_file_bis.borrow().commit(&mut context, &mut entries);
+            }
+        }));
+
+        InteractiveShell {
+            context,
+            shell: RefCell::new(shell),
+            terminated,
+            huponexit,
+            key_map,
+            prompt_cache,
+            commands_run,
+            last_command,
+            history_file,
+            new_entries,
+        }
+        .exec(prep_for_exit)
     }
 
     fn load_history(project_dir: &BaseDirectories, shell: &mut Shell, context: &mut Context) {
@@ -249,17 +381,87 @@ impl<'a> InteractiveShell<'a> {
         // Initialize the HISTFILE variable
         if let Some(histfile) = project_dir.find_data_file("history") {
             shell.variables_mut().set("HISTFILE", histfile.to_string_lossy().as_ref());
-            let _ = context.history.set_file_name_and_load_history(&histfile);
+            Self::load_history_file(context, &histfile);
         } else {
             match project_dir.place_data_file("history") {
                 Ok(histfile) => {
                     eprintln!("ion: creating history file at \"{}\"", histfile.display());
                     shell.variables_mut().set("HISTFILE", histfile.to_string_lossy().as_ref());
-                    let _ = context.history.set_file_name_and_load_history(&histfile);
+                    Self::load_history_file(context, &histfile);
                 }
                 Err(err) => println!("ion: could not create history file: {}", err),
             }
         }
+        // Only the most recent entries are loaded, the file is trimmed on exit
+        let cap =
+            shell.variables().get_str("HISTFILE_SIZE").ok().and_then(|size| size.parse().ok());
+        if let Some(cap) = cap {
+            let excess = context.history.buffers.len().saturating_sub(cap);
+            context.history.buffers.drain(..excess);
+        }
+    }
+
+    /// Loads the history from `histfile`, waiting a little for another session which would be
+    /// writing it at the same time
+    fn load_history_file(context: &mut Context, histfile: &Path) {
+        let _lock = (0..10).find_map(|_| match FileLock::try_lock(histfile, false) {
+            Ok(None) => {
+                thread::sleep(Duration::from_millis(10));
+                None
+            }
+            lock => Some(lock),
+        });
+        let _ = context.history.set_file_name_and_load_history(histfile);
+    }
+
+    /// The login profiles which exist, in the order they are sourced: the system profile first,
+    /// then the one of the user within `config_home`
+    fn login_files(system_file: &Path, config_home: &Path) -> Vec<PathBuf> {
+        let user_file = config_home.join("ion").join(Self::LOGIN_FILE_NAME);
+        vec![system_file.to_path_buf(), user_file]
+            .into_iter()
+            .filter(|file| file.is_file())
+            .collect()
+    }
+
+    fn exec_login_files(config_home: &Path, shell: &mut Shell) {
+        for file in Self::login_files(Path::new(Self::SYSTEM_LOGIN_FILE), config_home) {
+            match fs::File::open(&file) {
+                Ok(script) => {
+                    if let Err(err) = shell.execute_command(std::io::BufReader::new(script)) {
+                        eprintln!("ion: could not exec {}: {}", file.display(), err);
+                    }
+                }
+                Err(err) => eprintln!("ion: could not open {}: {}", file.display(), err),
+            }
+        }
+    }
+
+    fn persists_dir_stack(shell: &Shell) -> bool {
+        shell.variables().get_str("DIRSTACK_PERSIST").map_or(false, |persist| persist == "1")
+    }
+
+    /// Restore the directory stack of the previous session, if DIRSTACK_PERSIST is 1. The stack
+    /// is left empty if the saved one can't be read
+    fn load_dir_stack(project_dir: &BaseDirectories, shell: &mut Shell) {
+        if !Self::persists_dir_stack(shell) {
+            return;
+        }
+        if let Some(file) = project_dir.find_data_file(Self::DIRSTACK_FILE_NAME) {
+            let saved = fs::read_to_string(&file).unwrap_or_default();
+            if !shell.dir_stack_mut().load(&saved) {
+                eprintln!("ion: ignoring the invalid directory stack in {}", file.display());
+            }
+        }
+    }
+
+    /// Save the directory stack into `file` for the next session, if DIRSTACK_PERSIST is 1
+    fn save_dir_stack(shell: &Shell, file: &Path) {
+        if Self::persists_dir_stack(shell) {
+            if let Err(err) = fs::write(file, shell.dir_stack().save()) {
+                eprintln!("ion: could not save the directory stack: {}", err);
+            }
+        }
     }
 
     fn exec_init_file(project_dir: &BaseDirectories, shell: &mut Shell) {
@@ -282,6 +484,8 @@ impl<'a> InteractiveShell<'a> {
         let cmd: &str =
             &designators::expand_designators(&self.context.borrow(), command.trim_end());
         self.terminated.set(true);
+        self.commands_run.set(self.commands_run.get() + 1);
+        self.last_command.replace(cmd.to_string());
         {
             let mut shell = self.shell.borrow_mut();
             match shell.on_command(&cmd, true) {
@@ -318,6 +522,10 @@ impl<'a> InteractiveShell<'a> {
                     shell.reset_flow();
                 }
             }
+            // Keep the status of the command for the prompt, as `$?`
+            let status = shell.previous_status();
+            shell.variables_mut().set("LAST_STATUS", status);
+            self.history_file.replace(history::HistoryFile::new(shell.variables()));
         }
         self.save_command(&cmd);
     }
@@ -377,6 +585,8 @@ where
     iter:       I,
     count:      usize,
     word_start: Option<usize>,
+    /// The quote character of the quoted span currently being read, if any
+    quote:      Option<char>,
 }
 impl<I> WordDivide<I>
 where
@@ -384,15 +594,23 @@ where
 {
     #[inline]
     fn check_boundary(&mut self, c: char, index: usize, escaped: bool) -> Option<(usize, usize)> {
+        if !escaped {
+            match self.quote {
+                None if c == '"' || c == '\'' => self.quote = Some(c),
+                Some(quote) if quote == c => self.quote = None,
+                _ => (),
+            }
+        }
+
         if let Some(start) = self.word_start {
-            if c == ' ' && !escaped {
+            if c.is_whitespace() && !escaped && self.quote.is_none() {
                 self.word_start = None;
                 Some((start, index))
             } else {
                 self.next()
             }
         } else {
-            if c != ' ' {
+            if !c.is_whitespace() {
                 self.word_start = Some(index);
             }
             self.next()
@@ -426,8 +644,122 @@ where
     }
 }
 
+fn divide_words<I: Iterator<Item = char>>(chars: I) -> Vec<(usize, usize)> {
+    WordDivide { iter: chars.enumerate(), count: 0, word_start: None, quote: None }.collect()
+}
+
 fn word_divide(buf: &Buffer) -> Vec<(usize, usize)> {
     // -> impl Iterator<Item = (usize, usize)> + 'a
-    WordDivide { iter: buf.chars().copied().enumerate(), count: 0, word_start: None }.collect()
+    divide_words(buf.chars().copied())
     // TODO: return iterator directly :D
 }
+
+/// Indicates if a command which took `elapsed` should be reported, given the `REPORT_TIME`
+/// threshold in seconds. A threshold which is not a number disables the reports
+fn exceeds_report_time(threshold: &str, elapsed: Duration) -> bool {
+    threshold.parse::<f64>().map_or(false, |threshold| elapsed.as_secs_f64() > threshold)
+}
+
+thread_local! {
+    /// What to do when the interactive shell panics, set by `set_panic_hook`. The panic hook is
+    /// shared by all the threads, so the state of the shell can only be reached from here
+    static ON_PANIC: RefCell<Option<Box<dyn Fn()>>> = RefCell::new(None);
+}
+
+/// Runs `on_panic` once when the current thread panics, before the previous panic hook reports
+/// the panic. Unlike a guard dropped while unwinding, it also runs when panics abort, as they do
+/// in release builds
+fn set_panic_hook(on_panic: Box<dyn Fn()>) {
+    ON_PANIC.with(|hook| *hook.borrow_mut() = Some(on_panic));
+    let previous = panic::take_hook();
+    panic::set_hook(Box::new(move |info| {
+        let on_panic =
+            ON_PANIC.with(|hook| hook.try_borrow_mut().ok().and_then(|mut hook| hook.take()));
+        if let Some(on_panic) = on_panic {
+            on_panic();
+        }
+        previous(info);
+    }));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn slow_commands_exceed_report_time() {
+        let elapsed = Duration::from_millis(2_500);
+        assert!(exceeds_report_time("2", elapsed));
+        assert!(exceeds_report_time("0.5", elapsed));
+        assert!(!exceeds_report_time("2.5", elapsed));
+        assert!(!exceeds_report_time("10", elapsed));
+        assert!(!exceeds_report_time("", elapsed));
+        assert!(!exceeds_report_time("slow", elapsed));
+    }
+
+    #[test]
+    fn login_files_are_sourced_system_first() {
+        let temp = mktemp::Temp::new_dir().unwrap();
+        let home = temp.as_path();
+        let system_file = home.join("system_login");
+        let user_file = home.join("ion").join("login");
+        assert!(InteractiveShell::login_files(&system_file, home).is_empty());
+
+        fs::create_dir(home.join("ion")).unwrap();
+        fs::write(&user_file, "let FROM_USER = 1").unwrap();
+        assert_eq!(InteractiveShell::login_files(&system_file, home), vec![user_file.clone()]);
+
+        fs::write(&system_file, "let FROM_SYSTEM = 1").unwrap();
+        assert_eq!(InteractiveShell::login_files(&system_file, home), vec![system_file, user_file]);
+    }
+
+    #[test]
+    fn panic_hook_runs_once_when_a_builtin_panics() {
+        let restored = Rc::new(Cell::new(0));
+        let restored_bis = restored.clone();
+        set_panic_hook(Box::new(move || restored_bis.set(restored_bis.get() + 1)));
+        let explode: BuiltinFunction = &|_, _| panic!("explode");
+        let mut shell = InteractiveShell::new(Shell::default());
+        shell.shell.borrow_mut().builtins_mut().add("explode", explode, "Panic");
+        shell.exec_single_command("true");
+        assert_eq!(restored.get(), 0);
+
+        let result =
+            panic::catch_unwind(panic::AssertUnwindSafe(|| shell.exec_single_command("explode")));
+        assert!(result.is_err());
+        assert_eq!(restored.get(), 1);
+        assert!(panic::catch_unwind(|| panic!("again")).is_err());
+        assert_eq!(restored.get(), 1);
+    }
+
+    #[test]
+    fn last_status_is_set_after_each_command() {
+        let mut shell = InteractiveShell::new(Shell::default());
+        shell.shell.borrow_mut().variables_mut().set("HISTORY_IGNORE", array![]);
+        shell.exec_single_command("false");
+        assert_eq!(shell.shell.borrow().variables().get_str("LAST_STATUS").unwrap(), "1");
+        shell.exec_single_command("true");
+        assert_eq!(shell.shell.borrow().variables().get_str("LAST_STATUS").unwrap(), "0");
+    }
+
+    #[test]
+    fn word_divide_keeps_double_quoted_spans() {
+        assert_eq!(divide_words("echo \"foo bar\"".chars()), vec![(0, 4), (5, 14)]);
+    }
+
+    #[test]
+    fn word_divide_keeps_single_quoted_spans() {
+        assert_eq!(divide_words("echo 'a b' c".chars()), vec![(0, 4), (5, 10), (11, 12)]);
+    }
+
+    #[test]
+    fn word_divide_splits_on_tabs() {
+        assert_eq!(divide_words("cat\tfile".chars()), vec![(0, 3), (4, 8)]);
+        assert_eq!(divide_words("cat \t file".chars()), vec![(0, 3), (6, 10)]);
+    }
+
+    #[test]
+    fn word_divide_keeps_escaped_spaces() {
+        assert_eq!(divide_words("ls my\\ file".chars()), vec![(0, 2), (3, 11)]);
+    }
+}
@@ -1,59 +1,252 @@
 use super::InteractiveShell;
 use ion_shell::{
+    builtins::Status,
     expansion::{self, Expander},
-    IonError, PipelineError, Shell,
+    sys, types, IonError, PipelineError, Shell, Value,
 };
-use liner::{KeyBindings::*, Prompt, ViPromptMode, ViStatus};
+use liner::{
+    KeyBindings::{self, *},
+    Prompt, ViPromptMode, ViStatus,
+};
+use std::env;
+
+/// Prompts containing this marker are expanded every time, for instance to show a clock. The
+/// marker itself is not displayed.
+const NO_CACHE_MARKER: &str = "#nocache";
+
+/// The state a prompt was expanded in. The prompt is only expanded again when it changes.
+#[derive(Debug, Clone, PartialEq)]
+pub struct PromptFingerprint {
+    pwd:          Option<String>,
+    status:       Status,
+    commands_run: usize,
+    resizes:      usize,
+    vi_mode:      Option<types::Str>,
+}
+
+impl PromptFingerprint {
+    fn new(shell: &Shell<'_>, commands_run: usize) -> Self {
+        Self {
+            pwd:     env::var("PWD").ok(),
+            status:  shell.previous_status(),
+            commands_run,
+            resizes: sys::resizes(),
+            vi_mode: shell.variables().get_str("VI_MODE").ok(),
+        }
+    }
+}
 
 impl<'a> InteractiveShell<'a> {
+    /// Runs the command in `PROMPT_CMD` before the primary prompt is displayed, such as to update
+    /// the variables that it shows. The status of the previous command is kept for the prompt,
+    /// and the command is not reported like those of the user, such as with `RECORD_SUMMARY`. The
+    /// cached prompt is dropped after it, as the command may change what the prompt expands to
+    pub fn run_prompt_command(&self) {
+        let mut shell = self.shell.borrow_mut();
+        if !self.terminated.get() || shell.block_len() != 0 {
+            return;
+        }
+        let command = match shell.variables().get_str("PROMPT_CMD") {
+            Ok(command) if !command.trim().is_empty() => command,
+            _ => return,
+        };
+        let previous_status = shell.previous_status();
+        let on_command = shell.on_command_mut().take();
+        if let Err(err) = shell.execute_command(command.as_bytes()) {
+            eprintln!("ion: warning: PROMPT_CMD failed: {}", err);
+            // Do not leave an unterminated block open for the commands of the user
+            shell.reset_flow();
+        }
+        *shell.on_command_mut() = on_command;
+        shell.set_previous_status(previous_status);
+        *self.prompt_cache.borrow_mut() = None;
+    }
+
     /// Generates the prompt that will be used by Liner.
     pub fn prompt(&self) -> Prompt {
+        let key_bindings = self.context.borrow().key_bindings;
+        self.shell.borrow_mut().variables_mut().set("VI_MODE", vi_mode(key_bindings));
+        self.render_prompt(key_bindings, ViPromptMode::Insert)
+    }
+
+    /// Generates the prompt again after the vi mode changed to `mode` while a line is read, with
+    /// **VI_MODE** set to the new mode
+    pub fn vi_prompt(&self, mode: ViPromptMode) -> Prompt {
+        self.shell.borrow_mut().variables_mut().set("VI_MODE", vi_mode_name(&mode));
+        self.render_prompt(Vi, mode)
+    }
+
+    fn render_prompt(&self, key_bindings: KeyBindings, mode: ViPromptMode) -> Prompt {
         let mut shell = self.shell.borrow_mut();
         let previous_status = shell.previous_status();
         let blocks = if self.terminated.get() { shell.block_len() } else { shell.block_len() + 1 };
 
         if blocks == 0 {
-            let out =
-                shell.command("PROMPT", false).map(|res| res.to_string()).unwrap_or_else(|err| {
-                    if let expansion::Error::Subprocess(err) = err {
-                        if let IonError::PipelineExecutionError(PipelineError::CommandNotFound(_)) =
-                            *err
-                        {
-                            match shell
-                                .variables()
-                                .get_str("PROMPT")
-                                .and_then(|prompt| shell.get_string(&prompt))
-                            {
-                                Ok(prompt) => prompt.to_string(),
-                                Err(err) => {
-                                    eprintln!("ion: prompt expansion failed: {}", err);
-                                    ">>> ".into()
-                                }
-                            }
-                        } else {
-                            eprintln!("ion: prompt expansion failed: {}", err);
-                            ">>> ".into()
-                        }
-                    } else {
-                        panic!("Only a subprocess error should happen inside the pipeline");
+            let fingerprint = PromptFingerprint::new(&shell, self.commands_run.get());
+            let cached = match *self.prompt_cache.borrow() {
+                Some((ref cached, ref out)) if *cached == fingerprint => Some(out.clone()),
+                _ => None,
+            };
+            let out = cached.unwrap_or_else(|| {
+                // Only when the prompt is expanded again, as it takes a few system calls
+                shell.variables_mut().refresh_host_user();
+                let out = expand_prompt(&mut shell, "PROMPT", ">>> ");
+                // A prompt function opts out of the cache by printing the marker
+                let live = out.contains(NO_CACHE_MARKER)
+                    || ["PROMPT", "RPROMPT"].iter().any(|name| {
+                        shell
+                            .variables()
+                            .get_str(name)
+                            .map_or(false, |prompt| prompt.contains(NO_CACHE_MARKER))
+                    });
+                let out = out.replace(NO_CACHE_MARKER, "");
+                let out = match expand_rprompt(&mut shell) {
+                    Some(rprompt) => {
+                        let columns =
+                            termion::terminal_size().map_or(80, |(cols, _)| cols as usize);
+                        with_right_prompt(&out, &rprompt, columns)
                     }
-                });
+                    None => out,
+                };
+                *self.prompt_cache.borrow_mut() =
+                    if live { None } else { Some((fingerprint, out.clone())) };
+                out
+            });
             shell.set_previous_status(previous_status); // Set the previous exit code again
-            let key_bindings = self.context.borrow().key_bindings;
             match key_bindings {
                 Emacs => Prompt::from(out),
                 Vi => {
                     let normal = vi_prompt_indicator(&mut shell, ViPromptMode::Normal);
                     let insert = vi_prompt_indicator(&mut shell, ViPromptMode::Insert);
-                    Prompt {
-                        prompt:    out,
-                        vi_status: Some(ViStatus::new(ViPromptMode::Insert, normal, insert)),
+                    Prompt { prompt: out, vi_status: Some(ViStatus::new(mode, normal, insert)) }
+                }
+            }
+        } else {
+            // Deeper blocks are still shown by indenting after the continuation prompt
+            let out = expand_prompt(&mut shell, "PROMPT2", "> ").replace(NO_CACHE_MARKER, "");
+            shell.set_previous_status(previous_status); // Set the previous exit code again
+            Prompt::from(format!("{}{}", out, "    ".repeat(blocks - 1)))
+        }
+    }
+}
+
+/// Expands the prompt given by the function `name`, or else by the variable `name`. The primary
+/// prompt is rather given by the `prompt` function when it is defined. The output of a function
+/// keeps the `NO_CACHE_MARKER`, for the caller to find
+fn expand_prompt(shell: &mut Shell, name: &str, fallback: &str) -> String {
+    let previous_status = shell.previous_status();
+    let command = match shell.variables().get("prompt") {
+        Some(Value::Function(_)) if name == "PROMPT" => "prompt",
+        _ => name,
+    };
+    shell.command(command, false).map(|res| res.to_string()).unwrap_or_else(|err| {
+        if let expansion::Error::Subprocess(err) = err {
+            if let IonError::PipelineExecutionError(PipelineError::CommandNotFound(_)) = *err {
+                // Looking for the function changed the status that `$?` expands to
+                shell.set_previous_status(previous_status);
+                match shell
+                    .variables()
+                    .get_str(name)
+                    .and_then(|prompt| shell.get_string(&prompt.replace(NO_CACHE_MARKER, "")))
+                {
+                    Ok(prompt) => prompt.to_string(),
+                    Err(err) => {
+                        eprintln!("ion: prompt expansion failed: {}", err);
+                        fallback.into()
                     }
                 }
+            } else {
+                eprintln!("ion: prompt expansion failed: {}", err);
+                fallback.into()
             }
         } else {
-            Prompt::from("    ".repeat(blocks))
+            // Only a subprocess error should happen inside the pipeline
+            eprintln!("ion: prompt expansion failed: {}", err);
+            fallback.into()
         }
+    })
+}
+
+/// Expands RPROMPT like the PROMPT variable, if it is set and not empty
+fn expand_rprompt(shell: &mut Shell) -> Option<String> {
+    let rprompt =
+        shell.variables().get_str("RPROMPT").ok().filter(|rprompt| !rprompt.is_empty())?;
+    match shell.get_string(&rprompt.replace(NO_CACHE_MARKER, "")) {
+        Ok(expanded) => Some(expanded.to_string()),
+        Err(err) => {
+            eprintln!("ion: rprompt expansion failed: {}", err);
+            None
+        }
+    }
+}
+
+/// Removes the escape sequences, such as colors and window titles, from the text
+fn visible_text(text: &str) -> String {
+    let mut visible = String::with_capacity(text.len());
+    let mut chars = text.chars();
+    while let Some(c) = chars.next() {
+        if c != '\x1b' {
+            visible.push(c);
+            continue;
+        }
+        match chars.next() {
+            // Control sequences end with a byte in the range `@` to `~`
+            Some('[') => {
+                chars.by_ref().find(|c| ('@'..='~').contains(c));
+            }
+            // Operating system commands end with a bell or a string terminator
+            Some(']') => {
+                chars.by_ref().find(|&c| c == '\x07' || c == '\\');
+            }
+            _ => (),
+        }
+    }
+    visible
+}
+
+/// Draws `rprompt` flush right on the last line of `prompt`, given the width of the terminal.
+/// The cursor is saved and restored around the right prompt, so that the prompt is then drawn
+/// as usual. When the terminal is too narrow, only the end of the right prompt is kept, and it
+/// is left out if there is no room at all.
+fn with_right_prompt(prompt: &str, rprompt: &str, columns: usize) -> String {
+    let last_line = prompt.rsplit('\n').next().unwrap_or_default();
+    // Keep at least one blank column between both prompts
+    let available = columns.saturating_sub(visible_text(last_line).chars().count() + 1);
+    let visible = visible_text(rprompt);
+    let width = visible.chars().count();
+    let rprompt = if width <= available {
+        rprompt.to_string()
+    } else if available > 1 {
+        let tail: String = visible.chars().skip(width - available + 1).collect();
+        format!("…{}", tail)
+    } else {
+        return prompt.to_string();
+    };
+
+    let start = prompt.len() - last_line.len();
+    format!(
+        "{}\x1b7\x1b[{}G{}\x1b8{}",
+        &prompt[..start],
+        columns - width.min(available) + 1,
+        rprompt,
+        last_line
+    )
+}
+
+/// The value of the **VI_MODE** variable when a line starts being read. Liner starts reading
+/// each line in insert mode, and there is no mode with the emacs keybindings.
+pub const fn vi_mode(key_bindings: KeyBindings) -> &'static str {
+    match key_bindings {
+        Emacs => "",
+        Vi => vi_mode_name(&ViPromptMode::Insert),
+    }
+}
+
+/// The value of the **VI_MODE** variable in the vi `mode`
+const fn vi_mode_name(mode: &ViPromptMode) -> &'static str {
+    match mode {
+        ViPromptMode::Insert => "insert",
+        ViPromptMode::Normal => "normal",
     }
 }
 
@@ -91,3 +284,151 @@ fn vi_prompt_indicator(shell: &mut Shell, mode: ViPromptMode) -> String {
             String::new()
         })
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use nix::sys::signal::{self, Signal};
+
+    #[test]
+    fn continuation_prompt_is_used_for_unterminated_commands() {
+        let shell = InteractiveShell::new(Shell::default());
+        shell.shell.borrow_mut().variables_mut().set("PROMPT", "primary");
+        shell.shell.borrow_mut().variables_mut().set("PROMPT2", "${USER}> ");
+        assert_eq!(shell.prompt().prompt, "primary");
+
+        shell.terminated.set(false);
+        let user = shell.shell.borrow().variables().get_str("USER").unwrap();
+        assert_eq!(shell.prompt().prompt, format!("{}> ", user));
+    }
+
+    #[test]
+    fn vi_mode_is_exposed_to_the_prompt() {
+        let shell = InteractiveShell::new(Shell::default());
+        shell.shell.borrow_mut().variables_mut().set("PROMPT", "[${VI_MODE}]# ");
+        assert_eq!(shell.prompt().prompt, "[]# ");
+
+        shell.context.borrow_mut().key_bindings = Vi;
+        shell.commands_run.set(1);
+        assert_eq!(shell.prompt().prompt, "[insert]# ");
+        assert_eq!(shell.vi_prompt(ViPromptMode::Normal).prompt, "[normal]# ");
+        assert_eq!(shell.shell.borrow().variables().get_str("VI_MODE").unwrap(), "normal");
+        assert_eq!(shell.prompt().prompt, "[insert]# ");
+    }
+
+    #[test]
+    fn prompt_function_gives_the_prompt() {
+        let shell = InteractiveShell::new(Shell::default());
+        shell.shell.borrow_mut().variables_mut().set("PROMPT", "variable> ");
+        shell.shell.borrow_mut().variables_mut().set("PROMPT2", "variable2> ");
+        let function = "fn prompt\n    echo -n \"function> \"\nend\n";
+        shell.shell.borrow_mut().execute_command(function.as_bytes()).unwrap();
+        assert_eq!(shell.prompt().prompt, "function> ");
+
+        shell.terminated.set(false);
+        assert_eq!(shell.prompt().prompt, "variable2> ");
+    }
+
+    #[test]
+    fn prompt_function_output_can_opt_out_of_the_cache() {
+        let shell = InteractiveShell::new(Shell::default());
+        let function = "fn prompt\n    echo -n \"${COUNT}> \"\nend\n";
+        shell.shell.borrow_mut().execute_command(function.as_bytes()).unwrap();
+        shell.shell.borrow_mut().variables_mut().set("COUNT", "1");
+        assert_eq!(shell.prompt().prompt, "1> ");
+        shell.shell.borrow_mut().variables_mut().set("COUNT", "2");
+        assert_eq!(shell.prompt().prompt, "1> ");
+
+        let function = "fn prompt\n    echo -n \"${COUNT}> #nocache\"\nend\n";
+        shell.shell.borrow_mut().execute_command(function.as_bytes()).unwrap();
+        shell.commands_run.set(1);
+        assert_eq!(shell.prompt().prompt, "2> ");
+        shell.shell.borrow_mut().variables_mut().set("COUNT", "3");
+        assert_eq!(shell.prompt().prompt, "3> ");
+    }
+
+    #[test]
+    fn prompt_command_runs_before_the_prompt() {
+        let shell = InteractiveShell::new(Shell::default());
+        shell.shell.borrow_mut().variables_mut().set("PROMPT", "${STATE}> ");
+        shell.shell.borrow_mut().variables_mut().set("PROMPT_CMD", "let STATE = updated; false");
+        shell.shell.borrow_mut().set_previous_status(Status::SUCCESS);
+        shell.shell.borrow_mut().variables_mut().set("STATE", "cached");
+        assert_eq!(shell.prompt().prompt, "cached> ");
+        shell.run_prompt_command();
+        assert_eq!(shell.prompt().prompt, "updated> ");
+        assert_eq!(shell.shell.borrow().previous_status(), Status::SUCCESS);
+
+        shell.shell.borrow_mut().variables_mut().set("PROMPT_CMD", "if true");
+        shell.run_prompt_command();
+        assert_eq!(shell.shell.borrow().block_len(), 0);
+    }
+
+    #[test]
+    fn prompt_expands_the_previous_status() {
+        let shell = InteractiveShell::new(Shell::default());
+        shell.shell.borrow_mut().variables_mut().set("PROMPT", "${?} ${LAST_STATUS}> ");
+        shell.shell.borrow_mut().variables_mut().set("LAST_STATUS", "2");
+        shell.shell.borrow_mut().set_previous_status(Status::from_exit_code(2));
+        assert_eq!(shell.prompt().prompt, "2 2> ");
+        assert_eq!(shell.shell.borrow().previous_status(), Status::from_exit_code(2));
+    }
+
+    #[test]
+    fn prompt_is_expanded_again_when_the_state_changes() {
+        let mut shell = Shell::default();
+        let fingerprint = PromptFingerprint::new(&shell, 0);
+        assert_eq!(fingerprint, PromptFingerprint::new(&shell, 0));
+        assert_ne!(fingerprint, PromptFingerprint::new(&shell, 1));
+        shell.set_previous_status(Status::from_exit_code(1));
+        assert_ne!(fingerprint, PromptFingerprint::new(&shell, 0));
+        let moved = PromptFingerprint { pwd: Some("/elsewhere".into()), ..fingerprint.clone() };
+        assert_ne!(fingerprint, moved);
+    }
+
+    #[test]
+    fn resizing_the_terminal_invalidates_the_prompt() {
+        let shell = Shell::default();
+        sys::watch_resizes();
+        let fingerprint = PromptFingerprint::new(&shell, 0);
+        signal::raise(Signal::SIGWINCH).unwrap();
+        assert_ne!(fingerprint, PromptFingerprint::new(&shell, 0));
+    }
+
+    #[test]
+    fn prompt_is_cached_unless_marked() {
+        let shell = InteractiveShell::new(Shell::default());
+        shell.shell.borrow_mut().variables_mut().set("PROMPT", "first");
+        assert_eq!(shell.prompt().prompt, "first");
+        shell.shell.borrow_mut().variables_mut().set("PROMPT", "second#nocache");
+        assert_eq!(shell.prompt().prompt, "first");
+
+        shell.commands_run.set(1);
+        assert_eq!(shell.prompt().prompt, "second");
+        shell.shell.borrow_mut().variables_mut().set("PROMPT", "third#nocache");
+        assert_eq!(shell.prompt().prompt, "third");
+    }
+
+    #[test]
+    fn right_prompt_is_flush_right() {
+        assert_eq!(
+            with_right_prompt("$ ", "\x1b[1m~/src\x1b[0m", 20),
+            "\x1b7\x1b[16G\x1b[1m~/src\x1b[0m\x1b8$ "
+        );
+        assert_eq!(
+            with_right_prompt("user@host\n\x1b[32m$\x1b[0m ", "12:00", 10),
+            "user@host\n\x1b7\x1b[6G12:00\x1b8\x1b[32m$\x1b[0m "
+        );
+    }
+
+    #[test]
+    fn right_prompt_is_truncated_when_too_narrow() {
+        assert_eq!(with_right_prompt("dir> ", "abcdefgh", 10), "\x1b7\x1b[7G…fgh\x1b8dir> ");
+        assert_eq!(with_right_prompt("dir> ", "abcdefgh", 5), "dir> ");
+    }
+
+    #[test]
+    fn escape_sequences_are_not_visible() {
+        assert_eq!(visible_text("\x1b]0;title\x07\x1b[38;5;75mion\x1b[0m# "), "ion# ");
+    }
+}
@@ -1,7 +1,7 @@
-use super::{completer::IonCompleter, InteractiveShell};
+use super::{completer::IonCompleter, history, InteractiveShell};
 use ion_shell::Shell;
 use nix::fcntl::{fcntl, FcntlArg, OFlag};
-use std::io::ErrorKind;
+use std::io::{self, ErrorKind};
 
 impl<'a> InteractiveShell<'a> {
     /// Make sure to reset the fd to blocking mode
@@ -15,13 +15,24 @@ impl<'a> InteractiveShell<'a> {
         Self::change_blocking(0);
         Self::change_blocking(1);
         Self::change_blocking(2);
+        self.hide_ignored_history();
+        self.run_prompt_command();
         let prompt = self.prompt();
+        let key_bindings = self.context.borrow().key_bindings;
         let line = self.context.borrow_mut().read_line(
             prompt,
             None,
-            &mut IonCompleter::new(&self.shell.borrow()),
+            &mut IonCompleter::new(self, key_bindings),
         );
+        self.handle_line(line, prep_for_exit)
+    }
 
+    /// Handles the line read by liner, or the error which ended the read, such as an interrupt
+    fn handle_line<T: Fn(&mut Shell<'_>)>(
+        &self,
+        line: io::Result<String>,
+        prep_for_exit: &T,
+    ) -> Option<String> {
         match line {
             Ok(line) => {
                 if line.bytes().next() != Some(b'#')
@@ -29,10 +40,11 @@ impl<'a> InteractiveShell<'a> {
                 {
                     self.terminated.set(false);
                 }
-                Some(line)
+                // A multi-line command recalled from the history
+                Some(history::decode_entry(&line).into_owned())
             }
             // Handles Ctrl + C
-            Err(ref err) if err.kind() == ErrorKind::Interrupted => None,
+            Err(ref err) if err.kind() == ErrorKind::Interrupted => self.cancel_line(),
             // Handles Ctrl + D
             Err(ref err) if err.kind() == ErrorKind::UnexpectedEof => {
                 let mut shell = self.shell.borrow_mut();
@@ -48,4 +60,27 @@ impl<'a> InteractiveShell<'a> {
             }
         }
     }
+
+    /// Discard the line being edited after an interrupt, and move on to a fresh prompt. The
+    /// interrupt only cancels the line, so it must not be delivered to the next job
+    fn cancel_line(&self) -> Option<String> {
+        self.shell.borrow_mut().clear_interrupt();
+        println!();
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn interrupt_cancels_the_line() {
+        let shell = InteractiveShell::new(Shell::default());
+        let exit = |_: &mut Shell<'_>| panic!("an interrupt must not exit the shell");
+        let interrupt = || Err(io::Error::new(ErrorKind::Interrupted, "interrupted"));
+        assert_eq!(shell.handle_line(interrupt(), &exit), None);
+        assert_eq!(shell.handle_line(Ok("ls".into()), &exit), Some("ls".into()));
+        assert_eq!(shell.handle_line(interrupt(), &exit), None);
+    }
 }
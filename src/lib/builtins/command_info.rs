@@ -43,6 +43,31 @@ pub fn which(args: &[types::Str], shell: &mut Shell<'_>) -> Status {
     result
 }
 
+#[builtin(
+    desc = "describe what a name refers to",
+    man = "
+SYNOPSIS
+    whence NAME...
+
+DESCRIPTION
+    Describes each name: a shell variable with its type and value, an alias with its definition,
+    a function with its signature, or an environment variable. Fails if a name is not defined."
+)]
+pub fn whence(args: &[types::Str], shell: &mut Shell<'_>) -> Status {
+    if args.len() == 1 {
+        return Status::bad_argument("whence: Expected at least 1 args, got only 0");
+    }
+
+    let mut result = Status::SUCCESS;
+    for name in &args[1..] {
+        println!("{}", shell.variables().describe(name));
+        if shell.variables().get(name).is_none() && env::var_os(name.as_str()).is_none() {
+            result = Status::from_exit_code(1);
+        }
+    }
+    result
+}
+
 fn get_command_info<'a>(command: &str, shell: &mut Shell<'_>) -> Result<Cow<'a, str>, ()> {
     match shell.variables().get(command) {
         Some(Value::Alias(_)) => Ok("alias".into()),
@@ -12,6 +12,7 @@ mod is;
 mod job_control;
 mod math;
 mod random;
+mod read;
 mod set;
 mod source;
 mod status;
@@ -19,7 +20,7 @@ mod test;
 mod variables;
 
 pub use self::{
-    command_info::builtin_which,
+    command_info::{builtin_whence, builtin_which},
     conditionals::{builtin_contains, builtin_ends_with, builtin_starts_with},
     echo::builtin_echo,
     exists::builtin_exists,
@@ -28,6 +29,7 @@ pub use self::{
     is::builtin_is,
     man_pages::check_help,
     math::builtin_math,
+    read::builtin_read,
     set::builtin_set,
     source::builtin_source,
     status::builtin_status,
@@ -36,18 +38,17 @@ pub use self::{
 };
 use crate as ion_shell;
 use crate::{
-    shell::{Shell, Value},
+    shell::{colors::Colors, IonError, Shell, Value},
     types,
 };
 use builtins_proc::builtin;
 use itertools::Itertools;
-use liner::{Completer, Context, Prompt};
 use mktemp::Temp;
 use std::{
     borrow::Cow,
     collections::HashMap,
     fs::File,
-    io::{self, BufRead, BufReader},
+    io::{BufRead, BufReader},
     path::{Path, PathBuf},
     process::{Command, Stdio},
 };
@@ -160,13 +161,14 @@ impl<'a> BuiltinMap<'a> {
 
     /// Create and control variables
     ///
-    /// Contains `fn`, `alias`, `unalias`, `drop`, `read`
+    /// Contains `fn`, `alias`, `unalias`, `drop`, `read`, `debug`
     pub fn with_variables(&mut self) -> &mut Self {
         self.add("fn", &builtin_fn_, "Print list of functions")
             .add("alias", &builtin_alias, "View, set or unset aliases")
             .add("unalias", &builtin_unalias, "Delete an alias")
             .add("drop", &builtin_drop, "Delete a variable")
             .add("read", &builtin_read, "Read some variables\n    read <variable>")
+            .add("debug", &builtin_debug, "Show the internal state of the shell")
     }
 
     /// Control subrpocesses states
@@ -230,14 +232,16 @@ impl<'a> BuiltinMap<'a> {
 
     /// Basic utilities for any ion embedded library
     ///
-    /// Contains `help`, `source`, `status`, `echo`, `type`, `which`
+    /// Contains `help`, `colors`, `source`, `status`, `echo`, `type`, `which`
     pub fn with_basic(&mut self) -> &mut Self {
         self.add("help", &builtin_help, HELP_DESC)
+            .add("colors", &builtin_colors, "List the names of the colors")
             .add("source", &builtin_source, SOURCE_DESC)
             .add("status", &builtin_status, "Evaluates the current runtime status")
             .add("echo", &builtin_echo, "Display a line of text")
             .add("which", &builtin_which, "indicates what would be called for a given command")
             .add("type", &builtin_which, "indicates what would be called for a given command")
+            .add("whence", &builtin_whence, "Describe what a name refers to")
     }
 
     /// Utilities that may be a security risk. Not included by default
@@ -420,6 +424,9 @@ SYNOPSIS
 
 DESCRIPTION
     dirs prints the current directory stack.
+
+    When DIRSTACK_PERSIST is set to 1 in the init file, the directory stack is saved when the shell
+    exits, and restored below the current directory by the next interactive shell.
 "
 )]
 pub fn dirs(args: &[types::Str], shell: &mut Shell<'_>) -> Status {
@@ -624,47 +631,6 @@ pub fn popd(args: &[types::Str], shell: &mut Shell<'_>) -> Status {
     }
 }
 
-struct EmptyCompleter;
-
-impl Completer for EmptyCompleter {
-    fn completions(&mut self, _start: &str) -> Vec<String> { Vec::new() }
-}
-
-#[builtin(
-    desc = "read a line of input into some variables",
-    man = "
-SYNOPSIS
-    read VARIABLES...
-
-DESCRIPTION
-    For each variable reads from standard input and stores the results in the variable.
-"
-)]
-pub fn read(args: &[types::Str], shell: &mut Shell<'_>) -> Status {
-    if atty::is(atty::Stream::Stdin) {
-        let mut con = Context::new();
-        for arg in args.iter().skip(1) {
-            match con.read_line(Prompt::from(format!("{}=", arg.trim())), None, &mut EmptyCompleter)
-            {
-                Ok(buffer) => {
-                    shell.variables_mut().set(arg.as_ref(), buffer.trim());
-                }
-                Err(_) => return Status::FALSE,
-            }
-        }
-    } else {
-        let stdin = io::stdin();
-        let handle = stdin.lock();
-        let mut lines = handle.lines();
-        for arg in args.iter().skip(1) {
-            if let Some(Ok(line)) = lines.next() {
-                shell.variables_mut().set(arg.as_ref(), line.trim());
-            }
-        }
-    }
-    Status::SUCCESS
-}
-
 #[builtin(
     desc = "evaluates the specified commands",
     man = "
@@ -826,6 +792,51 @@ pub fn help(args: &[types::Str], shell: &mut Shell<'_>) -> Status {
     Status::SUCCESS
 }
 
+#[builtin(
+    desc = "show the internal state of the shell",
+    man = "
+SYNOPSIS
+    debug scopes
+
+DESCRIPTION
+    Shows the internal state of the shell, to diagnose scripts.
+
+    debug scopes prints one line per variable scope, starting from the global scope: its index,
+    whether it is active or was popped and kept to be reused, whether it is a namespace boundary,
+    past which outer variables can only be reached with super:: or global::, and the names defined
+    in it."
+)]
+pub fn debug(args: &[types::Str], shell: &mut Shell<'_>) -> Status {
+    match args.get(1).map(types::Str::as_str) {
+        Some("scopes") => {
+            print!("{}", shell.variables().debug_scopes());
+            Status::SUCCESS
+        }
+        Some(other) => Status::bad_argument(format!("debug: unknown state: {}", other)),
+        None => Status::bad_argument("debug: expected a state to show, such as scopes"),
+    }
+}
+
+#[builtin(
+    desc = "list the names of the colors",
+    man = "
+SYNOPSIS
+    colors
+
+DESCRIPTION
+    Lists the names of the colors and attributes of the c and color namespaces, each one shown in
+    its own color. Aliases such as grey or purple are listed as well."
+)]
+pub fn colors(_args: &[types::Str], _shell: &mut Shell<'_>) -> Status {
+    for name in Colors::names() {
+        match Colors::collect::<IonError>(name) {
+            Ok(color) => println!("{}{}\x1b[0m", color, name),
+            Err(_) => println!("{}", name),
+        }
+    }
+    Status::SUCCESS
+}
+
 use regex::Regex;
 #[builtin(
     desc = "checks if the second argument contains any proportion of the first",
@@ -0,0 +1,479 @@
+use super::Status;
+use crate as ion_shell;
+use crate::{shell::Shell, types};
+use builtins_proc::builtin;
+use liner::{Completer, Context, Prompt};
+use nix::{
+    errno::Errno,
+    poll::{poll, PollFd, PollFlags},
+    sys::termios::{self, LocalFlags, SetArg, SpecialCharacterIndices},
+    unistd,
+};
+use std::{
+    fs,
+    io::{self, Write},
+    os::unix::io::{AsRawFd, RawFd},
+    time::{Duration, Instant},
+};
+
+struct EmptyCompleter;
+
+impl Completer for EmptyCompleter {
+    fn completions(&mut self, _start: &str) -> Vec<String> { Vec::new() }
+}
+
+/// The options that may precede the variable names given to `read`
+#[derive(Debug, Default, PartialEq)]
+struct ReadOptions<'a> {
+    /// A custom prompt to display instead of `NAME=`
+    prompt:       Option<&'a str>,
+    /// Do not echo the input back to the terminal
+    silent:       bool,
+    /// Split a single line into the words of this array
+    array:        Option<&'a str>,
+    /// Give up if no complete line was read in this delay
+    timeout:      Option<Duration>,
+    /// Read until this byte instead of a newline
+    delim:        Option<u8>,
+    /// Return after reading this many characters, without waiting for the delimiter
+    nchars:       Option<usize>,
+    /// A file whose first line is the value to use when no input is given
+    default_file: Option<&'a str>,
+}
+
+impl<'a> ReadOptions<'a> {
+    /// The byte terminating each input
+    fn delimiter(&self) -> u8 { self.delim.unwrap_or(b'\n') }
+
+    /// The first line of the default file, if it could be read
+    fn default_value(&self) -> Option<String> {
+        let contents = fs::read_to_string(self.default_file?).ok()?;
+        Some(contents.lines().next().unwrap_or_default().to_string())
+    }
+}
+
+/// Parses the leading flags of `read`, returning the options and the remaining variable names
+fn parse_options(args: &[types::Str]) -> Result<(ReadOptions<'_>, &[types::Str]), String> {
+    let mut options = ReadOptions::default();
+    let mut index = 0;
+    while let Some(arg) = args.get(index) {
+        match arg.as_str() {
+            "-p" => {
+                index += 1;
+                let prompt = args.get(index).ok_or("read: -p requires a prompt")?;
+                options.prompt = Some(prompt.as_str());
+            }
+            "-s" => options.silent = true,
+            "-a" => {
+                index += 1;
+                let array = args.get(index).ok_or("read: -a requires an array name")?;
+                options.array = Some(array.as_str());
+            }
+            "-t" => {
+                index += 1;
+                let seconds = args.get(index).ok_or("read: -t requires a number of seconds")?;
+                match seconds.parse::<f64>() {
+                    Ok(secs) if secs.is_finite() && secs >= 0.0 => {
+                        options.timeout = Some(Duration::from_secs_f64(secs));
+                    }
+                    _ => return Err(format!("read: invalid timeout: {}", seconds)),
+                }
+            }
+            "-d" => {
+                index += 1;
+                let delim = args.get(index).ok_or("read: -d requires a delimiter")?;
+                // An empty delimiter reads up to a NUL byte, as needed for `find -print0`
+                options.delim = Some(delim.bytes().next().unwrap_or(b'\0'));
+            }
+            "-n" => {
+                index += 1;
+                let count = args.get(index).ok_or("read: -n requires a number of characters")?;
+                match count.parse::<usize>() {
+                    Ok(count) if count > 0 => options.nchars = Some(count),
+                    _ => return Err(format!("read: invalid number of characters: {}", count)),
+                }
+            }
+            "--default-file" => {
+                index += 1;
+                let path = args.get(index).ok_or("read: --default-file requires a path")?;
+                options.default_file = Some(path.as_str());
+            }
+            "--" => {
+                index += 1;
+                break;
+            }
+            _ => break,
+        }
+        index += 1;
+    }
+    Ok((options, &args[index..]))
+}
+
+/// Split `line` on the characters of `IFS` (whitespace by default) and store the words in the
+/// array `name`
+fn assign_array(shell: &mut Shell<'_>, name: &str, line: &str) {
+    let words = shell.variables().split_by_ifs(line.trim_end_matches(&['\n', '\r'][..]));
+    shell.variables_mut().set(name, words);
+}
+
+fn to_io(err: nix::Error) -> io::Error { io::Error::from_raw_os_error(err as i32) }
+
+/// Read from `fd` one byte at a time until the delimiter, the character limit or the end of the
+/// input is reached, so that nothing past the delimiter is consumed. Returns `None` if the input
+/// was not complete before the timeout expired, and an `UnexpectedEof` error if there was
+/// nothing left to read.
+fn read_until(fd: RawFd, options: &ReadOptions<'_>) -> io::Result<Option<String>> {
+    let delim = options.delimiter();
+    let deadline = options.timeout.map(|timeout| Instant::now() + timeout);
+    let mut buffer = Vec::new();
+    loop {
+        if let Some(deadline) = deadline {
+            let remaining = deadline.saturating_duration_since(Instant::now());
+            let mut fds = [PollFd::new(fd, PollFlags::POLLIN)];
+            match poll(&mut fds, remaining.as_millis() as i32) {
+                Ok(0) => return Ok(None),
+                Ok(_) | Err(Errno::EINTR) => (),
+                Err(err) => return Err(to_io(err)),
+            }
+        }
+
+        let mut byte = [0; 1];
+        match unistd::read(fd, &mut byte) {
+            Ok(0) if buffer.is_empty() => return Err(io::ErrorKind::UnexpectedEof.into()),
+            Ok(0) => break,
+            Ok(_) if byte[0] == delim => break,
+            Ok(_) => {
+                buffer.push(byte[0]);
+                let limit_reached = options.nchars.map_or(false, |nchars| {
+                    std::str::from_utf8(&buffer)
+                        .map_or(false, |text| text.chars().count() >= nchars)
+                });
+                if limit_reached {
+                    break;
+                }
+            }
+            Err(Errno::EINTR) => (),
+            Err(err) => return Err(to_io(err)),
+        }
+    }
+    Ok(Some(String::from_utf8_lossy(&buffer).into_owned()))
+}
+
+/// Read a line from the terminal without line editing, which allows disabling the echo, using a
+/// custom delimiter, limiting the number of characters and timing out. The terminal settings are
+/// restored afterwards.
+fn read_tty_line(prompt: &str, options: &ReadOptions<'_>) -> io::Result<Option<String>> {
+    let fd = io::stdin().as_raw_fd();
+    let original = termios::tcgetattr(fd).map_err(to_io)?;
+    let mut settings = original.clone();
+    if options.silent {
+        settings.local_flags.remove(LocalFlags::ECHO);
+    }
+    if options.nchars.is_some() {
+        // Deliver each key press immediately rather than waiting for Enter
+        settings.local_flags.remove(LocalFlags::ICANON);
+        settings.control_chars[SpecialCharacterIndices::VMIN as usize] = 1;
+        settings.control_chars[SpecialCharacterIndices::VTIME as usize] = 0;
+    }
+    termios::tcsetattr(fd, SetArg::TCSANOW, &settings).map_err(to_io)?;
+
+    print!("{}", prompt);
+    let _ = io::stdout().flush();
+    let result = read_until(fd, options);
+
+    let _ = termios::tcsetattr(fd, SetArg::TCSANOW, &original);
+    if options.silent || options.nchars.is_some() {
+        println!();
+    }
+    result
+}
+
+/// Read the variables from a non-interactive input
+fn read_from_fd(
+    shell: &mut Shell<'_>,
+    fd: RawFd,
+    options: &ReadOptions<'_>,
+    variables: &[types::Str],
+) -> Status {
+    let default = options.default_value();
+    // Empty input falls back to the default value, if there is one
+    let read_value = || match read_until(fd, options) {
+        Ok(Some(ref line)) if line.trim().is_empty() && default.is_some() => Ok(default.clone()),
+        Err(ref err) if err.kind() == io::ErrorKind::UnexpectedEof && default.is_some() => {
+            Ok(default.clone())
+        }
+        result => result,
+    };
+
+    if let Some(array) = options.array {
+        match read_value() {
+            Ok(Some(line)) => assign_array(shell, array, &line),
+            Ok(None) => return Status::FALSE,
+            Err(ref err) if err.kind() == io::ErrorKind::UnexpectedEof => (),
+            Err(why) => return Status::error(format!("read: {}", why)),
+        }
+    }
+    for arg in variables {
+        match read_value() {
+            Ok(Some(line)) => shell.variables_mut().set(arg.as_ref(), line.trim()),
+            Ok(None) => return Status::FALSE,
+            Err(ref err) if err.kind() == io::ErrorKind::UnexpectedEof => break,
+            Err(why) => return Status::error(format!("read: {}", why)),
+        }
+    }
+    Status::SUCCESS
+}
+
+#[builtin(
+    desc = "read a line of input into some variables",
+    man = "
+SYNOPSIS
+    read [-s] [-p PROMPT] [-t SECONDS] [-d DELIM] [-n NCHARS] [--default-file PATH] VARIABLES...
+    read [-s] [-p PROMPT] [-t SECONDS] [-d DELIM] [-n NCHARS] [--default-file PATH] -a ARRAY
+
+DESCRIPTION
+    For each variable reads from standard input and stores the results in the variable.
+
+OPTIONS
+    -a ARRAY
+        read a single line and store its words in ARRAY. Words are split on the characters of
+        the IFS variable, or on whitespace if IFS is not set
+    -d DELIM
+        read each value up to the first character of DELIM rather than a newline. An empty
+        DELIM reads up to a NUL byte. Line editing is not available from a terminal with -d
+    -n NCHARS
+        return after reading NCHARS characters instead of waiting for the delimiter. From a
+        terminal, Enter does not need to be pressed
+    -p PROMPT
+        display PROMPT instead of the variable name when reading from a terminal
+    -s
+        do not echo the input when reading from a terminal
+    -t SECONDS
+        fail if a complete line is not read within SECONDS, which may be fractional. The
+        variable is then left unchanged
+    --default-file PATH
+        use the first line of PATH as the default value. From a terminal, the default is
+        given as an editable input. Otherwise, it is used when the input is empty. A missing
+        file means there is no default"
+)]
+pub fn read(args: &[types::Str], shell: &mut Shell<'_>) -> Status {
+    let (options, variables) = match parse_options(&args[1..]) {
+        Ok(parsed) => parsed,
+        Err(why) => return Status::bad_argument(why),
+    };
+    if options.array.is_some() && !variables.is_empty() {
+        return Status::bad_argument("read: -a does not accept additional variables");
+    }
+
+    if atty::is(atty::Stream::Stdin) {
+        let mut con = Context::new();
+        let default = options.default_value();
+        let mut read_line = |name: &str| {
+            let prompt =
+                options.prompt.map_or_else(|| format!("{}=", name.trim()), ToString::to_string);
+            if options.silent
+                || options.timeout.is_some()
+                || options.delim.is_some()
+                || options.nchars.is_some()
+            {
+                read_tty_line(&prompt, &options).map(|line| match line {
+                    Some(ref line) if line.trim().is_empty() => default.clone(),
+                    line => line,
+                })
+            } else {
+                let buffer = default.as_deref().unwrap_or_default();
+                con.read_line_with_init_buffer(
+                    Prompt::from(prompt),
+                    None,
+                    &mut EmptyCompleter,
+                    buffer,
+                )
+                .map(Some)
+            }
+        };
+
+        if let Some(array) = options.array {
+            match read_line(array) {
+                Ok(Some(buffer)) => assign_array(shell, array, &buffer),
+                _ => return Status::FALSE,
+            }
+        }
+        for arg in variables {
+            match read_line(arg.as_str()) {
+                Ok(Some(buffer)) => {
+                    shell.variables_mut().set(arg.as_ref(), buffer.trim());
+                }
+                _ => return Status::FALSE,
+            }
+        }
+        Status::SUCCESS
+    } else {
+        read_from_fd(shell, io::stdin().as_raw_fd(), &options, variables)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::shell::variables::Value;
+
+    fn vec_string(args: &[&str]) -> Vec<types::Str> { args.iter().map(|s| (*s).into()).collect() }
+
+    #[test]
+    fn read_parses_prompt() {
+        let args = vec_string(&["-p", "Name: ", "NAME"]);
+        let (options, variables) = parse_options(&args).unwrap();
+        assert_eq!(options, ReadOptions { prompt: Some("Name: "), ..ReadOptions::default() });
+        assert_eq!(variables, &vec_string(&["NAME"])[..]);
+    }
+
+    #[test]
+    fn read_parses_silent() {
+        let args = vec_string(&["-s", "PASSWORD"]);
+        let (options, variables) = parse_options(&args).unwrap();
+        assert_eq!(options, ReadOptions { silent: true, ..ReadOptions::default() });
+        assert_eq!(variables, &vec_string(&["PASSWORD"])[..]);
+    }
+
+    #[test]
+    fn read_parses_silent_and_prompt() {
+        let args = vec_string(&["-s", "-p", "Password: ", "PASSWORD", "OTHER"]);
+        let (options, variables) = parse_options(&args).unwrap();
+        assert_eq!(
+            options,
+            ReadOptions { prompt: Some("Password: "), silent: true, ..ReadOptions::default() }
+        );
+        assert_eq!(variables, &vec_string(&["PASSWORD", "OTHER"])[..]);
+    }
+
+    #[test]
+    fn read_prompt_requires_argument() {
+        assert!(parse_options(&vec_string(&["-p"])).is_err());
+    }
+
+    #[test]
+    fn read_parses_array() {
+        let args = vec_string(&["-a", "WORDS"]);
+        let (options, variables) = parse_options(&args).unwrap();
+        assert_eq!(options, ReadOptions { array: Some("WORDS"), ..ReadOptions::default() });
+        assert!(variables.is_empty());
+    }
+
+    #[test]
+    fn read_array_splits_on_whitespace() {
+        let mut shell = Shell::default();
+        assign_array(&mut shell, "WORDS", "one  two\tthree\n");
+        assert_eq!(
+            shell.variables().get("WORDS"),
+            Some(&Value::Array(types_rs::array!["one", "two", "three"]))
+        );
+    }
+
+    #[test]
+    fn read_array_splits_on_custom_ifs() {
+        let mut shell = Shell::default();
+        shell.variables_mut().set("IFS", ":");
+        assign_array(&mut shell, "PARTS", "usr:local bin::lib");
+        assert_eq!(
+            shell.variables().get("PARTS"),
+            Some(&Value::Array(types_rs::array!["usr", "local bin", "", "lib"]))
+        );
+    }
+
+    #[test]
+    fn read_parses_timeout() {
+        let args = vec_string(&["-t", "0.5", "ANSWER"]);
+        let (options, _) = parse_options(&args).unwrap();
+        assert_eq!(options.timeout, Some(Duration::from_millis(500)));
+        assert!(parse_options(&vec_string(&["-t", "soon", "ANSWER"])).is_err());
+        assert!(parse_options(&vec_string(&["-t", "-1", "ANSWER"])).is_err());
+    }
+
+    #[test]
+    fn read_times_out_on_silent_input() {
+        let mut shell = Shell::default();
+        // Keep the write end open so that the read blocks instead of reaching the end of input
+        let (reader, _writer) = unistd::pipe().unwrap();
+        let args = vec_string(&["-t", "0.05", "ANSWER"]);
+        let (options, variables) = parse_options(&args).unwrap();
+
+        let status = read_from_fd(&mut shell, reader, &options, variables);
+        assert_eq!(status, Status::FALSE);
+        assert!(shell.variables().get("ANSWER").is_none());
+    }
+
+    #[test]
+    fn read_splits_on_nul_delimiter() {
+        let mut shell = Shell::default();
+        let (reader, writer) = unistd::pipe().unwrap();
+        unistd::write(writer, b"./one\0./two words\0").unwrap();
+        unistd::close(writer).unwrap();
+        let args = vec_string(&["-d", "", "FIRST", "SECOND", "THIRD"]);
+        let (options, variables) = parse_options(&args).unwrap();
+        assert_eq!(options.delim, Some(b'\0'));
+
+        let status = read_from_fd(&mut shell, reader, &options, variables);
+        unistd::close(reader).unwrap();
+        assert_eq!(status, Status::SUCCESS);
+        assert_eq!(shell.variables().get_str("FIRST").unwrap(), "./one");
+        assert_eq!(shell.variables().get_str("SECOND").unwrap(), "./two words");
+        assert!(shell.variables().get("THIRD").is_none());
+    }
+
+    #[test]
+    fn read_stops_after_nchars() {
+        let mut shell = Shell::default();
+        let (reader, writer) = unistd::pipe().unwrap();
+        unistd::write(writer, b"yes\n").unwrap();
+        unistd::close(writer).unwrap();
+        let args = vec_string(&["-n", "1", "CHOICE"]);
+        let (options, variables) = parse_options(&args).unwrap();
+
+        let status = read_from_fd(&mut shell, reader, &options, variables);
+        unistd::close(reader).unwrap();
+        assert_eq!(status, Status::SUCCESS);
+        assert_eq!(shell.variables().get_str("CHOICE").unwrap(), "y");
+    }
+
+    #[test]
+    fn read_uses_default_file_on_empty_input() {
+        let mut shell = Shell::default();
+        let file = mktemp::Temp::new_file().unwrap();
+        fs::write(file.as_path(), "default value\nsecond line\n").unwrap();
+        let path = file.as_path().display().to_string();
+        let (reader, writer) = unistd::pipe().unwrap();
+        unistd::close(writer).unwrap();
+        let args = vec_string(&["--default-file", &path, "VALUE"]);
+        let (options, variables) = parse_options(&args).unwrap();
+        assert_eq!(options.default_file, Some(path.as_str()));
+
+        let status = read_from_fd(&mut shell, reader, &options, variables);
+        unistd::close(reader).unwrap();
+        assert_eq!(status, Status::SUCCESS);
+        assert_eq!(shell.variables().get_str("VALUE").unwrap(), "default value");
+    }
+
+    #[test]
+    fn read_ignores_missing_default_file() {
+        let options = ReadOptions {
+            default_file: Some("/this/file/does/not/exist"),
+            ..ReadOptions::default()
+        };
+        assert_eq!(options.default_value(), None);
+    }
+
+    #[test]
+    fn read_assigns_partial_input_on_eof() {
+        let mut shell = Shell::default();
+        let (reader, writer) = unistd::pipe().unwrap();
+        unistd::write(writer, b"ab").unwrap();
+        unistd::close(writer).unwrap();
+        let args = vec_string(&["-n", "5", "CHOICE"]);
+        let (options, variables) = parse_options(&args).unwrap();
+
+        let status = read_from_fd(&mut shell, reader, &options, variables);
+        unistd::close(reader).unwrap();
+        assert_eq!(status, Status::SUCCESS);
+        assert_eq!(shell.variables().get_str("CHOICE").unwrap(), "ab");
+    }
+}
@@ -17,7 +17,7 @@ enum PositionalArgs {
     desc = "Set or unset values of shell options and positional parameters.",
     man = "
 SYNOPSIS
-    set [ --help ] [-e | +e] [- | --] [STRING]...
+    set [ --help ] [-e | +e] [-o OPTION | +o OPTION] [- | --] [STRING]...
 
 DESCRIPTION
     Shell options may be set using the '-' character, and unset using the '+' character.
@@ -25,6 +25,10 @@ DESCRIPTION
 OPTIONS
     -e  Exit immediately if a command exits with a non-zero status.
 
+    -o nocasevars
+        Ignore the case of ASCII letters in variable names, so that $path also expands PATH.
+        A variable spelled exactly as written is always preferred.
+
     --  Following arguments will be set as positional arguments in the shell.
         If no argument are supplied, arguments will be unset.
 
@@ -52,6 +56,13 @@ pub fn set(args: &[types::Str], shell: &mut Shell<'_>) -> Status {
             }
             "-e" => shell.opts_mut().err_exit = true,
             "+e" => shell.opts_mut().err_exit = false,
+            "-o" | "+o" => match args_iter.next().map(|option| option.as_str()) {
+                Some("nocasevars") => shell.variables_mut().set_case_insensitive(arg == "-o"),
+                Some(option) => {
+                    return Status::bad_argument(format!("set: unknown option '{}'", option))
+                }
+                None => return Status::bad_argument("set: an option must follow -o and +o"),
+            },
             _ => {
                 return Status::bad_argument(format!(
                     "set: argument '{}' is not recognized. Try adding `--` before it to pass it \
@@ -11,8 +11,9 @@ fn print_list(vars: &Variables) {
     let stdout = io::stdout();
     let stdout = &mut stdout.lock();
 
-    for (key, value) in vars.aliases() {
-        writeln!(stdout, "{} = {}", key, value).unwrap();
+    for (scope, key, value) in vars.aliases_with_scope() {
+        let local = if scope == 0 { "" } else { " (local)" };
+        writeln!(stdout, "{} = {}{}", key, value, local).unwrap();
     }
 }
 
@@ -136,6 +136,8 @@ pub trait Expander: Sized {
     fn map_keys(&self, _name: &str) -> Result<Args, Self::Error>;
     /// Iterating upon key-value maps.
     fn map_values(&self, _name: &str) -> Result<Args, Self::Error>;
+    /// The character joining the elements of a quoted array.
+    fn array_separator(&self) -> char { ' ' }
     /// Get a string that exists in the shell.
     fn get_string(&mut self, value: &str) -> Result<types::Str, Self::Error> {
         Ok(self.expand_string(value)?.join(" ").into())
@@ -392,7 +394,8 @@ trait ExpanderInternal: Expander {
                 };
                 let array = self.array(array, &index)?;
                 if quoted {
-                    Ok(args![types::Str::from(array.join(" "))])
+                    let separator = self.array_separator().to_string();
+                    Ok(args![types::Str::from(array.join(&separator))])
                 } else {
                     Ok(array)
                 }
@@ -808,7 +808,7 @@ impl<'a> Iterator for WordIterator<'a> {
                                         tilde,
                                     ))
                                 }
-                                Some(b'?') => {
+                                Some(b'?') | Some(b'$') | Some(b'!') => {
                                     start += 1;
                                     self.read += 1;
                                     return Some(WordToken::Variable(
@@ -175,6 +175,19 @@ fn test_words() {
     compare(input, expected);
 }
 
+#[test]
+fn special_variables() {
+    let input = "$? $$ \"$!\"";
+    let expected = &[
+        WordToken::Variable("?", None),
+        WordToken::Whitespace(" "),
+        WordToken::Variable("$", None),
+        WordToken::Whitespace(" "),
+        WordToken::Variable("!", None),
+    ];
+    compare(input, expected);
+}
+
 #[test]
 fn test_multiple_escapes() {
     let input = "foo\\(\\) bar\\(\\)";
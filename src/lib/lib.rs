@@ -89,6 +89,7 @@ pub mod builtins;
 pub mod expansion;
 mod memory;
 mod shell;
+mod version;
 
 pub use nix::sys::signal::Signal;
 
@@ -96,5 +97,6 @@ pub(crate) use self::memory::IonPool;
 pub use crate::{
     builtins::{BuiltinFunction, BuiltinMap},
     shell::*,
+    version::{version_info, VersionInfo},
 };
 pub use builtins_proc::builtin;
@@ -11,6 +11,7 @@ pub mod pipelines;
 mod quotes;
 mod statement;
 
+pub(crate) use self::statement::StatementVariant;
 pub use self::{
     quotes::Terminator,
     statement::{parse_and_validate, Error, StatementSplitter},
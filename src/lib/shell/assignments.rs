@@ -6,7 +6,7 @@ use crate::{
     assignments::*,
     builtins::Status,
     parser::lexers::assignments::{Key, Operator, Primitive},
-    shell::{flow_control::Function, Value, Variables},
+    shell::{flow_control::Function, variables::split_namespace, Value, Variables},
 };
 use std::{
     env,
@@ -71,6 +71,12 @@ impl<'b> Shell<'b> {
                 for action in actions {
                     let err = action.map_err(|e| e.to_string()).and_then(|act| {
                         let Action(key, operator, expression) = act;
+                        if self.variables.is_ephemeral(key.name) {
+                            return Err(format!(
+                                "{}: session-only variables can't be exported",
+                                key.name
+                            ));
+                        }
                         value_check(self, expression, &key.kind)
                             .map_err(|e| format!("{}: {}", key.name, e))
                             // TODO: handle operators here in the same way as local
@@ -100,6 +106,9 @@ impl<'b> Shell<'b> {
 
                 Status::SUCCESS
             }
+            ExportAction::LocalExport(ref key) if self.variables.is_ephemeral(key) => {
+                Status::error(format!("ion: cannot export {} because it is session-only.", key))
+            }
             ExportAction::LocalExport(ref key) => match self.variables.get_str(key) {
                 Ok(var) => {
                     env::set_var(key, &*var);
@@ -112,7 +121,7 @@ impl<'b> Shell<'b> {
             ExportAction::List => {
                 let stdout = io::stdout();
                 let mut stdout = stdout.lock();
-                for (key, val) in env::vars() {
+                for (key, val) in self.variables.build_child_env() {
                     let _ = writeln!(stdout, "{} = \"{}\"", key, val);
                 }
                 Status::SUCCESS
@@ -130,11 +139,12 @@ impl<'b> Shell<'b> {
             let Action(key, operator, expression) = action.map_err(|e| e.to_string())?;
 
             // sanitize variable names
-            if ["HOME", "HOST", "PWD", "MWD", "SWD", "?"].contains(&key.name) {
+            let (_, name) = split_namespace(key.name);
+            if ["HOME", "HOST", "PWD", "MWD", "SWD", "?"].contains(&name) {
                 return Err(format!("not allowed to set `{}`", key.name));
             }
 
-            if !Variables::is_valid_name(key.name) {
+            if !Variables::is_valid_name(name) {
                 return Err("invalid variable name: only alphanumerical characters and \
                             underscores are supported"
                     .to_string());
@@ -1,6 +1,6 @@
 use crate::expansion;
 use itertools::Itertools;
-use std::fmt;
+use std::{borrow::Cow, fmt};
 
 #[derive(Debug)]
 struct StaticMap {
@@ -71,6 +71,20 @@ const BG_COLORS: StaticMap = map!(
     "yellowbg" => "43"
 );
 
+/// Alternative spellings of the color names
+const ALIASES: StaticMap = map!(
+    "dark_grey" => "dark_gray",
+    "dark_greybg" => "dark_graybg",
+    "grey" => "light_gray",
+    "greybg" => "light_graybg",
+    "light_grey" => "light_gray",
+    "light_greybg" => "light_graybg",
+    "light_purple" => "light_magenta",
+    "light_purplebg" => "light_magentabg",
+    "purple" => "magenta",
+    "purplebg" => "magentabg"
+);
+
 #[derive(Debug, PartialEq)]
 /// Colors may be called by name, or by a hexadecimal-converted decimal value.
 enum Mode {
@@ -119,6 +133,17 @@ impl fmt::Display for Colors {
 }
 
 impl Colors {
+    /// All the names of colors and attributes, aliases included, in alphabetical order
+    pub fn names() -> Vec<&'static str> {
+        let mut names: Vec<_> = [&ATTRIBUTES, &COLORS, &BG_COLORS, &ALIASES]
+            .iter()
+            .flat_map(|map| map.keys.iter().copied())
+            .chain(Some("reset"))
+            .collect();
+        names.sort_unstable();
+        names
+    }
+
     /// If no matches were made, then this will attempt to parse the variable as either a
     /// 24-bit true color color, or one of 256 colors. It supports both hexadecimal and
     /// decimals.
@@ -184,6 +209,12 @@ impl Colors {
     ) -> expansion::Result<Self, T> {
         let mut colors = Self { foreground: None, background: None, attributes: Vec::new() };
         for variable in input.split(',') {
+            // `bg:color` is another way to write `colorbg`
+            let variable = match variable.strip_prefix("bg:") {
+                Some(color) => Cow::Owned(format!("{}bg", color)),
+                None => Cow::Borrowed(variable),
+            };
+            let variable = ALIASES.get(&variable).unwrap_or(&variable);
             if variable == "reset" {
                 return Ok(Self { foreground: None, background: None, attributes: vec!["0"] });
             } else if let Some(attribute) = ATTRIBUTES.get(variable) {
@@ -229,6 +260,31 @@ mod test {
     use super::*;
     use crate::shell::IonError;
 
+    #[test]
+    fn every_name_is_a_color() {
+        let names = Colors::names();
+        assert!(names.contains(&"bold") && names.contains(&"redbg") && names.contains(&"grey"));
+        for name in names {
+            assert!(Colors::collect::<IonError>(name).is_ok(), "{} is not a color", name);
+        }
+        assert_eq!(
+            Colors::collect::<IonError>("purple,greybg").unwrap(),
+            Colors::collect::<IonError>("magenta,light_graybg").unwrap()
+        );
+    }
+
+    #[test]
+    fn background_prefix() {
+        let actual = Colors::collect::<IonError>("bold,red,bg:blue").unwrap();
+        assert_eq!("\x1b[1;31;44m", actual.to_string());
+        let actual = Colors::collect::<IonError>("bg:0x55,bg:purple").unwrap();
+        assert_eq!(actual, Colors::collect::<IonError>("magentabg").unwrap());
+        let actual = Colors::collect::<IonError>("0xF00,bg:0x55").unwrap();
+        assert_eq!("\x1b[38;2;255;0;0;48;5;85m", actual.to_string());
+        assert!(Colors::collect::<IonError>("bold,bg:nocolor").is_err());
+        assert!(Colors::collect::<IonError>("bg:redbg").is_err());
+    }
+
     #[test]
     fn convert_hex_digit() {
         assert_eq!(Some(255), hex_char_to_u8_range('F'));
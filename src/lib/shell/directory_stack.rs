@@ -189,6 +189,32 @@ impl DirectoryStack {
 
     pub fn clear(&mut self) { self.dirs.truncate(1) }
 
+    /// The directories below the current one, one per line, to be restored by `load`.
+    /// Directories whose name contains a newline cannot be saved and are left out
+    pub fn save(&self) -> String {
+        self.dirs
+            .iter()
+            .skip(1)
+            .filter_map(|dir| dir.to_str())
+            .filter(|dir| !dir.contains('\n'))
+            .fold(String::new(), |saved, dir| saved + dir + "\n")
+    }
+
+    /// Replace the directories below the current one with those saved by `save`. If `saved` is
+    /// not a list of absolute paths, only the current directory is kept and false is returned
+    pub fn load(&mut self, saved: &str) -> bool {
+        self.clear();
+        let dirs: Vec<_> = saved.lines().map(PathBuf::from).collect();
+        if !dirs.iter().all(|dir| dir.is_absolute()) {
+            return false;
+        }
+        for dir in dirs {
+            let index = self.dirs.len();
+            self.insert_dir(index, dir);
+        }
+        true
+    }
+
     /// Create a new `DirectoryStack` containing the current working directory,
     /// if available.
     pub fn new() -> Self {
@@ -203,3 +229,29 @@ impl DirectoryStack {
         Self { dirs, max_depth: None }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn saved_stack_round_trip() {
+        let mut stack = DirectoryStack::new();
+        assert!(stack.load("/tmp\n/usr/lib\n"));
+        assert_eq!(
+            stack.dirs().skip(1).collect::<Vec<_>>(),
+            vec![Path::new("/tmp"), Path::new("/usr/lib")]
+        );
+        let saved = stack.save();
+        assert_eq!(saved, "/tmp\n/usr/lib\n");
+
+        let mut restored = DirectoryStack::new();
+        assert!(restored.load(&saved));
+        assert_eq!(restored, stack);
+
+        assert!(!restored.load("/tmp\nrelative/path\n"));
+        assert_eq!(restored.dirs().len(), 1);
+        assert!(restored.load(""));
+        assert_eq!(restored.save(), "");
+    }
+}
@@ -10,13 +10,15 @@ use crate::{
         pipelines::{PipeItem, Pipeline},
         Expander, ForValueExpression,
     },
-    parser::{parse_and_validate, StatementSplitter, Terminator},
+    parser::{
+        parse_and_validate, Error as ParseError, StatementSplitter, StatementVariant, Terminator,
+    },
     shell::{IonError, Job, Value},
     types,
 };
 use itertools::Itertools;
 use nix::unistd::Pid;
-use std::{rc::Rc, time::SystemTime};
+use std::{cell::Cell, rc::Rc, time::SystemTime};
 use thiserror::Error;
 
 #[derive(Debug, PartialEq, Eq, Hash, Clone, Copy)]
@@ -29,6 +31,17 @@ pub enum Condition {
 
 type Result = std::result::Result<Condition, IonError>;
 
+/// A syntax error found by `Shell::check_syntax`, with the position where it was found
+#[derive(Debug)]
+pub struct SyntaxError {
+    /// The line of the error, counting from 1
+    pub line:   usize,
+    /// The column of the error in characters, counting from 1
+    pub column: usize,
+    /// The error itself
+    pub error:  IonError,
+}
+
 /// The block order was invalid
 #[derive(Debug, Error, PartialEq, Eq, Hash)]
 pub enum BlockError {
@@ -582,6 +595,106 @@ impl<'a> Shell<'a> {
 
         Ok(())
     }
+
+    /// Parses `script` without executing it, as for `ion -n`, and returns all the syntax errors
+    /// with their position. The blocks are followed to find the unmatched `end` and the unclosed
+    /// blocks, but nothing is defined nor executed
+    pub fn check_syntax(&self, script: &str) -> Vec<SyntaxError> {
+        let consumed = Cell::new(0);
+        let mut bytes = script.bytes().inspect(|_| consumed.set(consumed.get() + 1));
+        let mut block = Block::default();
+        // The offsets of the statements opening the blocks
+        let mut opened = Vec::new();
+        let mut errors = Vec::new();
+        let mut error_at = |offset: usize, error: IonError| {
+            let (line, column) = line_and_column(script, offset);
+            errors.push(SyntaxError { line, column, error });
+        };
+
+        loop {
+            let start = consumed.get();
+            let stmt = match Terminator::new(&mut bytes).terminate() {
+                Some(stmt) => stmt,
+                None => break,
+            };
+            let mut cursor = skip_blank(script, start);
+            for statement in StatementSplitter::new(&stmt) {
+                let statement = match statement {
+                    Ok(statement) => statement,
+                    Err(error) => {
+                        let offset = match error {
+                            ParseError::InvalidCharacter(character, _) => {
+                                script[cursor..].find(character).map_or(cursor, |pos| cursor + pos)
+                            }
+                            _ => cursor,
+                        };
+                        error_at(offset, error.into());
+                        continue;
+                    }
+                };
+                let text = match statement {
+                    StatementVariant::And(text)
+                    | StatementVariant::Or(text)
+                    | StatementVariant::Default(text) => text,
+                };
+                let (offset, len) = locate(script, cursor, text);
+                cursor = offset + len;
+                let inserted = parse_and_validate(statement, &self.builtins)
+                    .map_err(IonError::from)
+                    .and_then(|statement| {
+                        Self::insert_statement(&mut block, statement).map_err(IonError::from)
+                    });
+                match inserted {
+                    Ok(_) => {
+                        opened.truncate(block.len());
+                        opened.resize(block.len(), offset);
+                    }
+                    Err(error) => error_at(offset, error),
+                }
+            }
+        }
+
+        if let (Some(statement), Some(&offset)) = (block.last(), opened.last()) {
+            error_at(offset, BlockError::UnclosedBlock(statement.to_string()).into());
+        }
+        errors
+    }
+}
+
+/// The line and the column in characters, counting from 1, of the byte `offset` of `text`
+fn line_and_column(text: &str, offset: usize) -> (usize, usize) {
+    let before = &text[..offset];
+    let line_start = before.rfind('\n').map_or(0, |pos| pos + 1);
+    (before.matches('\n').count() + 1, before[line_start..].chars().count() + 1)
+}
+
+/// The offset of the first character of `script` from `from` which is neither whitespace nor
+/// part of a comment
+fn skip_blank(script: &str, mut from: usize) -> usize {
+    loop {
+        let rest = &script[from..];
+        let trimmed = rest.trim_start();
+        from += rest.len() - trimmed.len();
+        if !trimmed.starts_with('#') {
+            return from;
+        }
+        from += trimmed.find('\n').unwrap_or(trimmed.len());
+    }
+}
+
+/// The offset and the length of `statement` in `script` from `from`. Its whitespace may have
+/// been collapsed, in which case only its first word is found. If neither is found, this is the
+/// next character which is not blank
+fn locate(script: &str, from: usize, statement: &str) -> (usize, usize) {
+    let rest = &script[from..];
+    if let Some(pos) = rest.find(statement) {
+        return (from + pos, statement.len());
+    }
+    let word = statement.split_whitespace().next().unwrap_or_default();
+    match rest.find(word) {
+        Some(pos) if !word.is_empty() => (from + pos, word.len()),
+        _ => (skip_blank(script, from), 0),
+    }
 }
 
 /// Expand a pipeline containing aliases. As aliases can split the pipeline by having logical
@@ -756,4 +869,20 @@ mod tests {
             assert!(Shell::insert_statement(&mut flow_control, err).is_err());
         }
     }
+
+    #[test]
+    fn syntax_errors_are_located() {
+        let shell = Shell::default();
+        let valid = "# a comment\nif test 1 = 1\n    echo 'a; b'\nend\n";
+        assert!(shell.check_syntax(valid).is_empty());
+
+        let errors = shell.check_syntax("echo ok\n  end\nfor i in 1..3\n    echo $i\n");
+        let positions: Vec<_> = errors.iter().map(|error| (error.line, error.column)).collect();
+        assert_eq!(positions, vec![(2, 3), (3, 1)]);
+        assert!(matches!(errors[0].error, IonError::StatementFlowError(BlockError::UnmatchedEnd)));
+        assert!(matches!(
+            errors[1].error,
+            IonError::StatementFlowError(BlockError::UnclosedBlock(_))
+        ));
+    }
 }
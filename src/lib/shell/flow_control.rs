@@ -293,6 +293,20 @@ impl Function {
         res.map(|_| ())
     }
 
+    /// The name of the function followed by its arguments, with the type of those which are
+    /// not strings, as in `square x:int`
+    #[must_use]
+    pub fn signature(&self) -> String {
+        let mut signature = self.name.to_string();
+        for arg in &self.args {
+            match arg.kind {
+                Primitive::Str => signature.push_str(&format!(" {}", arg.name)),
+                ref kind => signature.push_str(&format!(" {}:{}", arg.name, kind)),
+            }
+        }
+        signature
+    }
+
     /// Get the function's description
     #[must_use]
     pub const fn description(&self) -> Option<&types::Str> { self.description.as_ref() }
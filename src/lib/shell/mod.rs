@@ -1,5 +1,5 @@
 mod assignments;
-mod colors;
+pub(crate) mod colors;
 mod directory_stack;
 mod flow;
 /// The various blocks
@@ -20,7 +20,7 @@ use self::{
     variables::Variables,
 };
 pub use self::{
-    flow::BlockError,
+    flow::{BlockError, SyntaxError},
     job::{Job, RefinedJob},
     pipe_exec::{
         job_control::{BackgroundEvent, BackgroundProcess},
@@ -443,6 +443,14 @@ impl<'a> Shell<'a> {
         }
     }
 
+    /// Forget an interrupt received while no job was running, such as while a line was being
+    /// read, so that it does not abort the next job. Returns true if there was one
+    pub fn clear_interrupt(&mut self) -> bool {
+        signals::PENDING
+            .compare_exchange(signals::SIGINT as usize, 0, Ordering::SeqCst, Ordering::SeqCst)
+            .is_ok()
+    }
+
     /// Get the last command's return code and/or the code for the error
     pub fn set_previous_status(&mut self, status: Status) { self.previous_status = status; }
 
@@ -496,8 +504,7 @@ impl<'a> Shell<'a> {
             | (_, Value::Array(_))
             | (Primitive::HashMap(_), Value::HashMap(_))
             | (Primitive::BTreeMap(_), Value::BTreeMap(_)) => {
-                self.variables.set(key.name, value);
-                Ok(())
+                self.variables.try_set(key.name, value)
             }
             _ => Ok(()),
         }
@@ -213,6 +213,7 @@ impl<'a> Shell<'a> {
         let pid = process.pid();
         let njob = self.add_to_background(process);
         self.previous_job = njob;
+        self.variables.set_last_background_pid(pid);
         if let Some(ref callback) = &self.background_event {
             callback(njob, pid, BackgroundEvent::Added);
         }
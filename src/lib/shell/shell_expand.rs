@@ -63,6 +63,8 @@ impl<'a, 'b> Expander for Shell<'b> {
         }
     }
 
+    fn array_separator(&self) -> char { self.variables().array_separator() }
+
     /// Expand an array variable with some selection
     fn array(
         &self,
@@ -271,3 +273,45 @@ impl<'a, 'b> Expander for Shell<'b> {
         .map(|home: types::Str| home + rest)
     }
 }
+
+impl<'a> Shell<'a> {
+    /// Expand the tilde at the start of each element of a colon-separated list of paths, such as
+    /// `~/bin:~/.local/bin` for `PATH`. Elements which can't be expanded are kept as-is
+    #[must_use]
+    pub fn expand_tildes_in_pathlist(&self, value: &str) -> String {
+        value
+            .split(':')
+            .map(|path| match self.tilde(path) {
+                Ok(expanded) => expanded.to_string(),
+                Err(_) => path.to_string(),
+            })
+            .collect::<Vec<_>>()
+            .join(":")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn tildes_in_pathlist() {
+        let shell = Shell::default();
+        let home = shell.tilde("~").unwrap();
+        assert_eq!(
+            shell.expand_tildes_in_pathlist("~/bin:/usr/bin:~/.local/bin"),
+            format!("{0}/bin:/usr/bin:{0}/.local/bin", home)
+        );
+        assert_eq!(shell.expand_tildes_in_pathlist("/bin::/sbin"), "/bin::/sbin");
+    }
+
+    #[test]
+    fn quoted_arrays_are_joined_with_ifs() {
+        let mut shell = Shell::default();
+        shell.variables_mut().set("PATHS", types_rs::array!["/bin", "/usr/bin"]);
+        assert_eq!(shell.get_string("\"@PATHS\"").unwrap(), "/bin /usr/bin");
+        shell.variables_mut().set("IFS", ":");
+        assert_eq!(shell.get_string("\"@PATHS\"").unwrap(), "/bin:/usr/bin");
+        assert_eq!(shell.get_array("@PATHS").unwrap(), args!["/bin", "/usr/bin"]);
+    }
+}
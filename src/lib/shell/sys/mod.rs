@@ -1,4 +1,18 @@
-//! System specific shell variables for NULL_PATH
+//! System specific shell variables for NULL_PATH and the current user, the terminal settings and
+//! resizes, and the locks of the files shared between shells
+use nix::sys::{
+    signal::{self, SigHandler, Signal},
+    termios::{self, SetArg, Termios},
+};
+use std::{
+    fs::File,
+    io,
+    path::Path,
+    sync::atomic::{AtomicUsize, Ordering},
+};
+
+/// The number of times the terminal was resized, counted by the handler of `watch_resizes`
+static RESIZES: AtomicUsize = AtomicUsize::new(0);
 
 #[cfg(target_os = "redox")]
 /// NULL_PATH on Redox OS
@@ -6,3 +20,83 @@ pub const NULL_PATH: &str = "null:";
 #[cfg(all(unix, not(target_os = "redox")))]
 /// NULL_PATH on Unix systems
 pub const NULL_PATH: &str = "/dev/null";
+
+/// The name of the user running the shell, if it can be determined
+#[cfg(all(unix, not(target_os = "redox")))]
+pub fn username() -> Option<String> {
+    users::get_current_username().map(|name| name.to_string_lossy().into_owned())
+}
+
+/// The name of the user running the shell, if it can be determined
+#[cfg(target_os = "redox")]
+pub fn username() -> Option<String> {
+    let users = redox_users::AllUsers::basic(redox_users::Config::default()).ok()?;
+    redox_users::get_uid().ok().and_then(|id| users.get_by_id(id)).map(|user| user.user.clone())
+}
+
+/// The settings of the terminal attached to the standard input, saved to be restored later, such
+/// as after a builtin which changed the echo or the input mode failed to reset them
+#[derive(Clone)]
+pub struct TerminalMode(Option<Termios>);
+
+impl TerminalMode {
+    /// Save the current settings. Nothing is saved if the standard input is not a terminal
+    pub fn save() -> Self { Self(termios::tcgetattr(0).ok()) }
+
+    /// Put the terminal back in the saved settings
+    pub fn restore(&self) {
+        if let Some(settings) = &self.0 {
+            let _ = termios::tcsetattr(0, SetArg::TCSANOW, settings);
+        }
+    }
+}
+
+/// Count the resizes of the terminal from now on, so that what depends on its width, such as the
+/// prompt, can be rendered again. The handler only counts the SIGWINCH signals, so it is safe to
+/// be interrupted by itself. Nothing is redrawn by the handler: a line being edited keeps its
+/// prompt, and the new width is only taken into account by the next prompt
+pub fn watch_resizes() {
+    extern "C" fn handler(_signal: i32) { RESIZES.fetch_add(1, Ordering::SeqCst); }
+
+    unsafe {
+        let _ = signal::signal(Signal::SIGWINCH, SigHandler::Handler(handler));
+    }
+}
+
+/// The number of times the terminal was resized since `watch_resizes` was called
+pub fn resizes() -> usize { RESIZES.load(Ordering::SeqCst) }
+
+/// An advisory lock of a file shared by several shells, such as the history file, which is
+/// released when dropped
+pub struct FileLock {
+    /// The file whose lock is released when it is closed
+    _file: File,
+}
+
+impl FileLock {
+    /// Lock `path`, shared to read it or exclusive to write it, without waiting. Returns None if
+    /// another shell holds a conflicting lock
+    #[cfg(all(unix, not(target_os = "redox")))]
+    pub fn try_lock(path: &Path, exclusive: bool) -> io::Result<Option<Self>> {
+        use nix::{
+            errno::Errno,
+            fcntl::{flock, FlockArg},
+        };
+        use std::os::unix::io::AsRawFd;
+
+        let file = File::open(path)?;
+        let arg =
+            if exclusive { FlockArg::LockExclusiveNonblock } else { FlockArg::LockSharedNonblock };
+        match flock(file.as_raw_fd(), arg) {
+            Ok(()) => Ok(Some(Self { _file: file })),
+            Err(Errno::EWOULDBLOCK) => Ok(None),
+            Err(err) => Err(io::Error::from(err)),
+        }
+    }
+
+    /// Files can not be locked on Redox, so the lock is always granted
+    #[cfg(target_os = "redox")]
+    pub fn try_lock(path: &Path, _exclusive: bool) -> io::Result<Option<Self>> {
+        File::open(path).map(|file| Some(Self { _file: file }))
+    }
+}
@@ -1,39 +1,147 @@
-use super::{colors::Colors, flow_control::Function};
+use super::{colors::Colors, flow_control::Function, sys};
 use crate::{
     expansion,
     shell::IonError,
     types::{self, Array},
 };
-use nix::unistd::{geteuid, gethostname, getpid, getuid};
+use nix::unistd::{geteuid, gethostname, getpid, getuid, Pid};
 use scopes::{Namespace, Scope, Scopes};
-use std::{env, ffi::CStr, rc::Rc, convert::TryFrom};
+use std::{
+    borrow::Cow,
+    collections::{BTreeMap, HashMap, HashSet},
+    convert::TryFrom,
+    env,
+    ops::{Deref, DerefMut, Range},
+    rc::Rc,
+};
+use types_rs::Modifications;
 use unicode_segmentation::UnicodeSegmentation;
 
 /// Contain a dynamically-typed variable value
 pub use types_rs::Value;
+
+/// The flag of the scopes making variable names case-insensitive
+const NOCASEVARS: u8 = 1;
 /// A structure containing dynamically-typed values organised in scopes
-pub struct Variables(Scopes<types::Str, Value<Rc<Function>>>);
+pub struct Variables {
+    scopes:               Scopes<types::Str, Value<Rc<Function>>>,
+    /// The pid of the last job sent to the background, expanded by `$!`
+    last_background_pid:  Option<Pid>,
+    /// Warn when a name is shadowed more than this many times across the live scopes
+    shadow_warning_limit: Option<usize>,
+    /// Session-only names, which are never exported to the environment of child processes
+    ephemeral:            HashSet<types::Str>,
+    /// The hooks run when the variable of a given name changes
+    change_hooks:         HashMap<types::Str, Vec<ChangeHook>>,
+}
+
+/// A hook run with the name and the new value of a variable when it changes, or with
+/// `Value::None` when it is removed. It cannot reach the variables, so it cannot trigger itself
+pub type ChangeHook = Box<dyn Fn(&str, &Value<Rc<Function>>)>;
+
+/// Lookups of plain variables memoized for the duration of one expansion, obtained with
+/// `Variables::begin_expansion`
+#[derive(Debug, Default)]
+pub struct ExpansionCache {
+    values: HashMap<types::Str, Option<Value<Rc<Function>>>>,
+    /// The number of lookups which went through the scopes
+    walks:  usize,
+}
+
+/// A scope entered with `Variables::scoped`, which is exited when the guard is dropped, even by
+/// an early return or a panic. The variables are reached through the guard meanwhile
+pub struct ScopeGuard<'a> {
+    variables: &'a mut Variables,
+    /// The number of scopes before this one was entered
+    depth:     usize,
+}
+
+impl Deref for ScopeGuard<'_> {
+    type Target = Variables;
+
+    fn deref(&self) -> &Variables { self.variables }
+}
+
+impl DerefMut for ScopeGuard<'_> {
+    fn deref_mut(&mut self) -> &mut Variables { self.variables }
+}
+
+impl Drop for ScopeGuard<'_> {
+    fn drop(&mut self) {
+        // Also exit the scopes left open inside this one
+        while self.variables.scope_depth() > self.depth {
+            self.variables.pop_scope();
+        }
+    }
+}
 
 impl Variables {
+    /// Create the default variables, plus a string variable in the global scope for each
+    /// variable of the process environment. The variables computed by ion, such as `PID` or
+    /// `PROMPT`, keep their value
+    #[must_use]
+    pub fn with_env() -> Self {
+        let mut variables = Self::default();
+        for (name, value) in env::vars() {
+            if variables.scopes.get(name.as_str(), Namespace::Global).is_none() {
+                variables.scopes.set(name, Value::Str(value.into()));
+            }
+        }
+        variables
+    }
+
+    /// Get the visible definition of each name, from the innermost scope outward. A name
+    /// shadowed by an inner scope is only yielded once, with its innermost value
+    fn visible(&self) -> impl Iterator<Item = (&types::Str, &Value<Rc<Function>>)> {
+        let mut seen = HashSet::new();
+        self.scopes
+            .scopes()
+            .flat_map(|scope| scope.iter())
+            .filter(move |(key, _)| seen.insert(*key))
+    }
+
     /// Get all strings
     pub fn string_vars(&self) -> impl Iterator<Item = (&types::Str, &types::Str)> {
-        self.0.scopes().flat_map(|map| {
-            map.iter().filter_map(|(key, val)| {
-                if let types_rs::Value::Str(val) = val {
-                    Some((key, val))
-                } else {
-                    None
-                }
-            })
+        self.visible().filter_map(|(key, val)| {
+            if let types_rs::Value::Str(val) = val {
+                Some((key, val))
+            } else {
+                None
+            }
         })
     }
 
     /// Get all aliases
     pub fn aliases(&self) -> impl Iterator<Item = (&types::Str, &types::Str)> {
-        self.0.scopes().rev().flat_map(|map| {
-            map.iter().filter_map(|(key, possible_alias)| {
+        self.visible().filter_map(|(key, possible_alias)| {
+            if let types_rs::Value::Alias(alias) = possible_alias {
+                Some((key, &**alias))
+            } else {
+                None
+            }
+        })
+    }
+
+    /// Get the names of all aliases as owned strings, so that the variables can be modified
+    /// while going through them
+    #[must_use]
+    pub fn alias_names(&self) -> Vec<types::Str> {
+        self.aliases().map(|(name, _)| name.clone()).collect()
+    }
+
+    /// Get the names of all the functions as owned strings, so that the variables can be
+    /// modified while going through them
+    #[must_use]
+    pub fn function_names(&self) -> Vec<types::Str> {
+        self.functions().map(|(name, _)| name.clone()).collect()
+    }
+
+    /// Get all aliases along with the index of the scope defining them, 0 being the global scope
+    pub fn aliases_with_scope(&self) -> impl Iterator<Item = (usize, &types::Str, &types::Str)> {
+        self.scopes.scopes().rev().enumerate().flat_map(|(index, map)| {
+            map.iter().filter_map(move |(key, possible_alias)| {
                 if let types_rs::Value::Alias(alias) = possible_alias {
-                    Some((key, &**alias))
+                    Some((index, key, &**alias))
                 } else {
                     None
                 }
@@ -41,66 +149,166 @@ impl Variables {
         })
     }
 
+    /// Get the alias named by the first word of `command`, with the number of bytes of
+    /// `command` up to the end of that word, leading whitespace included
+    #[must_use]
+    pub fn first_word_alias(&self, command: &str) -> Option<(types::Alias, usize)> {
+        let start = command.len() - command.trim_start().len();
+        let length = command[start..].find(char::is_whitespace).unwrap_or(command.len() - start);
+        match self.get(&command[start..start + length]) {
+            Some(Value::Alias(alias)) => Some((alias.clone(), start + length)),
+            _ => None,
+        }
+    }
+
     /// Get all the functions
     pub fn functions(&self) -> impl Iterator<Item = (&types::Str, &Rc<Function>)> {
-        self.0.scopes().rev().flat_map(|map| {
-            map.iter().filter_map(|(key, val)| {
-                if let types_rs::Value::Function(val) = val {
-                    Some((key, val))
-                } else {
-                    None
-                }
-            })
+        self.visible().filter_map(|(key, val)| {
+            if let types_rs::Value::Function(val) = val {
+                Some((key, val))
+            } else {
+                None
+            }
         })
     }
 
     /// Get all the variables
     pub fn variables(&self) -> impl Iterator<Item = (&types::Str, &Value<Rc<Function>>)> {
-        self.0.scopes().rev().flat_map(|map| {
-            map.iter().filter_map(|(key, val)| match val {
-                val @ Value::Array(_)
-                | val @ Value::Str(_)
-                | val @ Value::HashMap(_)
-                | val @ Value::BTreeMap(_) => Some((key, val)),
-                _ => None,
-            })
+        self.visible().filter_map(|(key, val)| match val {
+            val @ Value::Array(_)
+            | val @ Value::Str(_)
+            | val @ Value::HashMap(_)
+            | val @ Value::BTreeMap(_) => Some((key, val)),
+            _ => None,
         })
     }
 
+    /// Describe the scope stack for debugging, one line per scope from the global one: its
+    /// index, whether it is active or popped and kept for reuse, whether it is a namespace
+    /// boundary, and the sorted names it defines
+    #[must_use]
+    pub fn debug_scopes(&self) -> String {
+        let current = self.scopes.current_index();
+        let mut output = String::new();
+        for (index, scope) in self.scopes.all_scopes().iter().enumerate() {
+            let mut names: Vec<_> = scope.keys().map(|name| name.as_str()).collect();
+            names.sort_unstable();
+            output.push_str(&format!(
+                "scope {} {}{}: {}\n",
+                index,
+                if index <= current { "active" } else { "inactive" },
+                if scope.is_namespace() { " namespace" } else { "" },
+                names.join(" ")
+            ));
+        }
+        output
+    }
+
+    /// Get the names of the visible variables matching the glob `pattern`, such as `PROMPT*`.
+    /// Each name is listed once, even if it is shadowed, and only if its visible definition is a
+    /// variable rather than an alias or a function. An invalid pattern only matches itself
+    pub fn keys_matching<'a>(&'a self, pattern: &str) -> impl Iterator<Item = &'a types::Str> + 'a {
+        let matcher = glob::Pattern::new(pattern).ok();
+        let pattern = pattern.to_owned();
+        self.visible()
+            .filter(move |(name, value)| {
+                !matches!(value, Value::Alias(_) | Value::Function(_) | Value::None)
+                    && matcher.as_ref().map_or(name.as_str() == pattern, |glob| glob.matches(name))
+            })
+            .map(|(name, _)| name)
+    }
+
     /// Get all the array values
     pub fn arrays(&self) -> impl Iterator<Item = (&types::Str, &types::Array<Rc<Function>>)> {
-        self.0.scopes().rev().flat_map(|map| {
-            map.iter().filter_map(|(key, val)| {
-                if let types_rs::Value::Array(val) = val {
-                    Some((key, val))
-                } else {
-                    None
-                }
-            })
+        self.visible().filter_map(|(key, val)| {
+            if let types_rs::Value::Array(val) = val {
+                Some((key, val))
+            } else {
+                None
+            }
         })
     }
 
     /// Create a new scope. If namespace is true, variables won't be droppable across the scope
     /// boundary
-    pub fn new_scope(&mut self, namespace: bool) { self.0.new_scope(namespace) }
+    pub fn new_scope(&mut self, namespace: bool) { self.scopes.new_scope(namespace) }
 
     /// Exit the current scope
-    pub fn pop_scope(&mut self) { self.0.pop_scope() }
+    pub fn pop_scope(&mut self) { self.scopes.pop_scope() }
+
+    /// Enter a new scope like `new_scope`, which is exited when the returned guard is dropped
+    pub fn scoped(&mut self, namespace: bool) -> ScopeGuard<'_> {
+        let depth = self.scope_depth();
+        self.new_scope(namespace);
+        ScopeGuard { variables: self, depth }
+    }
+
+    /// The number of live scopes, the global scope included
+    #[must_use]
+    pub fn scope_depth(&self) -> usize { self.scopes.scopes().count() }
 
     pub(crate) fn pop_scopes(
         &mut self,
         index: usize,
     ) -> impl Iterator<Item = Scope<types::Str, Value<Rc<Function>>>> + '_ {
-        self.0.pop_scopes(index)
+        self.scopes.pop_scopes(index)
     }
 
     pub(crate) fn append_scopes(&mut self, scopes: Vec<Scope<types::Str, Value<Rc<Function>>>>) {
-        self.0.append_scopes(scopes)
+        self.scopes.append_scopes(scopes)
+    }
+
+    /// Clone the visible scopes, from the global scope up to the current one, so that an
+    /// evaluation can be forked without touching this stack
+    #[must_use]
+    pub fn snapshot(&self) -> Vec<Scope<types::Str, Value<Rc<Function>>>> {
+        self.scopes.snapshot()
+    }
+
+    /// Swap all the variables of the current scope at once, returning the previous ones, such as
+    /// to run a command with some variables and restore the scope afterwards. Outer scopes are
+    /// left untouched
+    pub fn replace_scope_vars(
+        &mut self,
+        vars: HashMap<types::Str, Value<Rc<Function>>>,
+    ) -> HashMap<types::Str, Value<Rc<Function>>> {
+        self.scopes.replace_current(vars)
+    }
+
+    /// Remove all the variables of the current scope, aliases and functions included, leaving
+    /// outer scopes untouched. Unlike `pop_scope`, the scope itself stays the current one, and
+    /// remains a namespace if it was one
+    pub fn clear_locals(&mut self) {
+        if let Some(scope) = self.scopes.scopes_mut().next() {
+            scope.clear();
+        }
+    }
+
+    /// Copy the variables of the current scope of `other`, aliases and functions included, into
+    /// the current scope. Only the current scopes are involved, not the whole stacks. A variable
+    /// already in the current scope is replaced only if `overwrite` is true
+    pub fn merge(&mut self, other: &Self, overwrite: bool) {
+        let vars = match other.scopes.scopes().next() {
+            Some(scope) => scope,
+            None => return,
+        };
+        for (name, value) in vars.iter() {
+            let exists =
+                self.scopes.scopes().next().map_or(false, |scope| scope.contains_key(name));
+            if overwrite || !exists {
+                self.scopes.set(name.clone(), value.clone());
+            }
+        }
+    }
+
+    /// Replace the scope stack with the scopes of a `snapshot`
+    pub fn restore(&mut self, scopes: Vec<Scope<types::Str, Value<Rc<Function>>>>) {
+        self.scopes.restore(scopes)
     }
 
     #[must_use]
     pub(crate) fn index_scope_for_var(&self, name: &str) -> Option<usize> {
-        self.0.index_scope_for_var(name)
+        self.scopes.index_scope_for_var(name)
     }
 
     /// Set a variable to a value in the current scope. If a variable already exists in a writable
@@ -108,11 +316,323 @@ impl Variables {
     /// shadowing other variables
     pub fn set<T: Into<Value<Rc<Function>>>>(&mut self, name: &str, value: T) {
         let value = value.into();
-        if let Some(val) = self.0.get_mut(name) {
+        if !self.warn_on_function_clobber(name, &value) {
+            self.note_on_shadowing(name, &value);
+        }
+        if let Some(val) = self.get_mut(name) {
             let _ = std::mem::replace(val, value);
+        } else if let Err(why) = self.create(name, value) {
+            eprintln!("ion: {}", why);
+            return;
+        }
+        self.notify(name);
+    }
+
+    /// Register a hook to run after the variable `name` is set, appended to or removed, such as
+    /// to derive another variable from it. Hooks run in the order they were registered
+    pub fn on_change(&mut self, name: &str, hook: ChangeHook) {
+        self.change_hooks.entry(name.into()).or_default().push(hook);
+    }
+
+    /// Run the hooks registered for `name` with its value as now visible
+    fn notify(&self, name: &str) {
+        let (_, bare) = split_namespace(name);
+        if let Some(hooks) = self.change_hooks.get(bare) {
+            let none = Value::None;
+            let value = self.get(name).unwrap_or(&none);
+            for hook in hooks {
+                hook(bare, value);
+            }
+        }
+    }
+
+    /// Set a variable like `set`, but only if `name` is not already defined in a visible scope,
+    /// such as to give a default value. Returns whether the variable was set
+    pub fn set_if_unset<T: Into<Value<Rc<Function>>>>(&mut self, name: &str, value: T) -> bool {
+        if self.get(name).is_some() {
+            return false;
+        }
+        match self.create(name, value.into()) {
+            Ok(()) => {
+                self.notify(name);
+                true
+            }
+            Err(why) => {
+                eprintln!("ion: {}", why);
+                false
+            }
+        }
+    }
+
+    /// Append a value to a variable: a string is concatenated to a string, the elements of an
+    /// array or a string are pushed to an array, and the entries of a map are inserted into a
+    /// map of the same kind, replacing those with the same keys. An unset variable is set like
+    /// with `set`, and a value of another type replaces the variable, with a warning
+    pub fn append<T: Into<Value<Rc<Function>>>>(&mut self, name: &str, value: T) {
+        let value = value.into();
+        let current = match self.get_mut(name) {
+            Some(current) => current,
+            None => return self.set(name, value),
+        };
+        match (current, value) {
+            (Value::HashMap(map), Value::HashMap(entries)) => map.extend(entries),
+            (Value::BTreeMap(map), Value::BTreeMap(entries)) => map.extend(entries),
+            (current @ Value::Array(_), value @ Value::Array(_))
+            | (current @ Value::Array(_), value @ Value::Str(_))
+            | (current @ Value::Str(_), value @ Value::Str(_)) => {
+                current.append(value);
+            }
+            (current, value) => {
+                eprintln!(
+                    "ion: cannot append {} to the {} `{}`, replacing it",
+                    type_name(&value),
+                    type_name(current),
+                    name
+                );
+                *current = value;
+            }
+        }
+        self.notify(name);
+    }
+
+    /// Get a mutable access to the array `name`, creating an empty array like `set` if it is
+    /// unset. Returns None if `name` is set to a value of another type, or cannot be created
+    pub fn get_or_insert_array(&mut self, name: &str) -> Option<&mut types::Array<Rc<Function>>> {
+        if self.get_mut(name).is_none() {
+            if let Err(why) = self.create(name, Value::Array(types::Array::new())) {
+                eprintln!("ion: {}", why);
+                return None;
+            }
+        }
+        match self.get_mut(name)? {
+            Value::Array(array) => Some(array),
+            _ => None,
+        }
+    }
+
+    /// Set the element `index` of the array `name` in place, padding the array with empty strings
+    /// if it is shorter, and creating it if it is unset. Returns false if `name` is set to a value
+    /// of another type, or cannot be created
+    pub fn set_array_element(
+        &mut self,
+        name: &str,
+        index: usize,
+        value: Value<Rc<Function>>,
+    ) -> bool {
+        let array = match self.get_or_insert_array(name) {
+            Some(array) => array,
+            None => return false,
+        };
+        if index >= array.len() {
+            array.resize(index + 1, Value::Str(types::Str::new()));
+        }
+        array[index] = value;
+        self.notify(name);
+        true
+    }
+
+    /// Create a variable in the current scope, or in the namespace designated by a `super::` or
+    /// `global::` qualifier. Variables whose name could never be expanded are refused
+    fn create(&mut self, name: &str, value: Value<Rc<Function>>) -> Result<(), String> {
+        let is_variable = !matches!(value, Value::Alias(_) | Value::Function(_));
+        if is_variable && name != "?" && !is_valid_reference(name) {
+            return Err(format!("invalid variable name: {}", name));
+        }
+        match split_namespace(name) {
+            (Namespace::Any, _) => {
+                self.scopes.set(name, value);
+                self.warn_on_deep_shadowing(name);
+                Ok(())
+            }
+            (namespace, bare) => {
+                if self.scopes.set_in_namespace(bare.into(), value, namespace) {
+                    Ok(())
+                } else {
+                    Err(format!("cannot set `{}`: there is no such namespace", name))
+                }
+            }
+        }
+    }
+
+    /// Store a value as-is in the current scope, without updating a variable of an outer scope
+    /// like `set`, for callers which know exactly what they want to store
+    pub fn set_typed(&mut self, name: &str, value: Value<Rc<Function>>) {
+        self.scopes.set(name, value);
+        self.warn_on_deep_shadowing(name);
+        self.notify(name);
+    }
+
+    /// Set a variable like `set`, replacing the type of an existing binding in place. If the
+    /// `STRICT_TYPES` variable is set to 1, changing the type of an existing variable is an
+    /// error instead, to catch values accidentally assigned to the wrong variable.
+    ///
+    /// Only the assignments of `let` go through this check: `set` itself, used by the builtins
+    /// and the library, always replaces the value whatever its type
+    pub fn try_set<T: Into<Value<Rc<Function>>>>(
+        &mut self,
+        name: &str,
+        value: T,
+    ) -> Result<(), String> {
+        let value = value.into();
+        let strict = self.get_str("STRICT_TYPES").map_or(false, |strict| strict == "1");
+        if !self.warn_on_function_clobber(name, &value) {
+            self.note_on_shadowing(name, &value);
+        }
+        if let Some(current) = self.get_mut(name) {
+            let (from, to) = (type_name(current), type_name(&value));
+            if strict && from != to {
+                return Err(format!(
+                    "cannot change the type of `{}` from {} to {}",
+                    name, from, to
+                ));
+            }
+            *current = value;
+        } else {
+            self.create(name, value)?;
+        }
+        self.notify(name);
+        Ok(())
+    }
+
+    /// Enable a diagnostic warning when a variable is shadowed more than `limit` times across the
+    /// live scopes, which usually points to a logic error in a recursive script. Disabled by
+    /// default
+    pub fn set_shadow_warning_limit(&mut self, limit: Option<usize>) {
+        self.shadow_warning_limit = limit;
+    }
+
+    /// Print a warning if `name` is shadowed more times than allowed. Returns true if it was
+    fn warn_on_deep_shadowing(&self, name: &str) -> bool {
+        let limit = match self.shadow_warning_limit {
+            Some(limit) => limit,
+            None => return false,
+        };
+        let shadowed = self.scopes.scopes().filter(|scope| scope.contains_key(name)).count() - 1;
+        if shadowed > limit {
+            eprintln!("ion: warning: variable '{}' is shadowed {} times", name, shadowed);
+            true
+        } else {
+            false
+        }
+    }
+
+    /// Print a warning if `WARN_SHADOW` is set to 1 and a value which is not a function is about
+    /// to replace or shadow the visible function `name`. Returns true if it was
+    fn warn_on_function_clobber(&self, name: &str, value: &Value<Rc<Function>>) -> bool {
+        if let Value::Function(_) = value {
+            return false;
+        }
+        match self.get(name) {
+            Some(Value::Function(_))
+                if self.get_str("WARN_SHADOW").map_or(false, |warn| warn == "1") =>
+            {
+                eprintln!("ion: warning: the {} `{}` replaces a function", type_name(value), name);
+                true
+            }
+            _ => false,
+        }
+    }
+
+    /// Print a note if `SHADOW_WARN` is set to 1 and a value of another kind is about to replace
+    /// or shadow the visible function or alias `name`. Returns true if it was
+    fn note_on_shadowing(&self, name: &str, value: &Value<Rc<Function>>) -> bool {
+        let shadowed = match (self.get(name), value) {
+            (Some(Value::Function(_)), Value::Function(_))
+            | (Some(Value::Alias(_)), Value::Alias(_)) => return false,
+            (Some(Value::Function(_)), _) => "a function",
+            (Some(Value::Alias(_)), _) => "an alias",
+            _ => return false,
+        };
+        if self.get_str("SHADOW_WARN").map_or(false, |warn| warn == "1") {
+            eprintln!("ion: note: '{}' now shadows {}", name, shadowed);
+            true
         } else {
-            self.0.set(name, value);
+            false
+        }
+    }
+
+    /// Set the positional arguments (`@args`, `$1`..`$N`) of the current scope, the first one being
+    /// the name of the command. They are dropped along with the scope, restoring those of the
+    /// caller
+    pub fn push_positional_args<I: IntoIterator<Item = String>>(&mut self, args: I) {
+        let args: Array<_> = args.into_iter().map(|arg| Value::Str(arg.into())).collect();
+        self.scopes.set("args", args);
+    }
+
+    /// The number of positional arguments (`$#`), not counting the name of the command
+    #[must_use]
+    pub fn positional_count(&self) -> usize {
+        match self.get("args") {
+            Some(Value::Array(args)) => args.len().saturating_sub(1),
+            _ => 0,
+        }
+    }
+
+    /// Record the pid of the last job sent to the background, for `$!`
+    pub(crate) fn set_last_background_pid(&mut self, pid: Pid) {
+        self.last_background_pid = Some(pid);
+    }
+
+    /// Mark a variable as session-only, such as a secret. It is removed from the environment if
+    /// it was exported, and can no longer be exported to child processes
+    pub fn mark_ephemeral(&mut self, name: &str) {
+        env::remove_var(name);
+        self.ephemeral.insert(name.into());
+    }
+
+    /// Indicates if the variable was marked as session-only
+    #[must_use]
+    pub fn is_ephemeral(&self, name: &str) -> bool { self.ephemeral.contains(name) }
+
+    /// The environment given to child processes, without any session-only variable
+    #[must_use]
+    pub fn build_child_env(&self) -> Vec<(String, String)> {
+        env::vars().filter(|(key, _)| !self.is_ephemeral(key)).collect()
+    }
+
+    /// All the string variables and the environment variables as sorted `NAME=VALUE` lines,
+    /// with the values quoted when needed. A shell variable hides the environment variable of
+    /// the same name, and session-only variables are left out
+    #[must_use]
+    pub fn export_string_vars(&self) -> String {
+        let mut vars: BTreeMap<String, String> = env::vars().collect();
+        // The outermost scopes come first, so that the innermost definition wins
+        for scope in self.scopes.scopes().rev() {
+            for (name, value) in scope.iter() {
+                if let Value::Str(value) = value {
+                    vars.insert(name.to_string(), value.to_string());
+                }
+            }
+        }
+        vars.iter()
+            .filter(|(name, _)| !self.is_ephemeral(name))
+            .map(|(name, value)| format!("{}={}\n", name, quote(value)))
+            .collect()
+    }
+
+    /// The visible string, array and map variables as a JSON object sorted by name, for external
+    /// tools to inspect. Maps are serialized as nested objects. Aliases and functions are left
+    /// out, as they have no JSON counterpart, and so are session-only variables
+    #[must_use]
+    pub fn to_json(&self) -> String {
+        let mut vars = BTreeMap::new();
+        // The outermost scopes come first, so that the innermost definition wins
+        for scope in self.scopes.scopes().rev() {
+            for (name, value) in scope.iter() {
+                match value {
+                    _ if self.is_ephemeral(name) => (),
+                    Value::Str(_) | Value::Array(_) | Value::HashMap(_) | Value::BTreeMap(_) => {
+                        vars.insert(name, value);
+                    }
+                    _ => {
+                        vars.remove(name);
+                    }
+                }
+            }
         }
+        let mut json = String::new();
+        write_json_object(&mut json, vars);
+        json
     }
 
     /// Obtains the value for the **MWD** variable.
@@ -122,30 +642,8 @@ impl Variables {
     /// of the current directory will be reduced to a single character.
     #[must_use]
     fn get_minimal_directory(&self) -> types::Str {
-        let swd = self.get_simplified_directory();
-
-        {
-            // Temporarily borrow the `swd` variable while we attempt to assemble a minimal
-            // variant of the directory path. If that is not possible, we will cancel the
-            // borrow and return `swd` itself as the minified path.
-            let elements = swd.split('/').filter(|s| !s.is_empty()).collect::<Vec<&str>>();
-            if elements.len() > 2 {
-                let mut output = types::Str::new();
-                for element in &elements[..elements.len() - 1] {
-                    let mut segmenter = UnicodeSegmentation::graphemes(*element, true);
-                    let grapheme = segmenter.next().unwrap();
-                    output.push_str(grapheme);
-                    if grapheme == "." {
-                        output.push_str(segmenter.next().unwrap());
-                    }
-                    output.push('/');
-                }
-                output.push_str(elements[elements.len() - 1]);
-                return output;
-            }
-        }
-
-        swd
+        let home = self.get_str("HOME").unwrap_or_else(|_| "?".into());
+        compact_path(&env::var("PWD").unwrap(), &home, 1).into()
     }
 
     /// Obtains the value for the **SWD** variable.
@@ -154,20 +652,34 @@ impl Variables {
     /// working directory which the leading `HOME` prefix replaced with a tilde character.
     #[must_use]
     fn get_simplified_directory(&self) -> types::Str {
-        let home = self.get_str("HOME").unwrap_or_else(|_| "?".into()).to_string();
-        let home = std::path::Path::new(&home);
-
-        let pwd = env::var("PWD").unwrap();
-        let pwd = std::path::Path::new(&pwd);
-        
-        if pwd.starts_with(home) {
-            pwd.to_str().unwrap().replacen(home.to_str().unwrap(), "~", 1).into()
-        } else {
-            pwd.to_str().unwrap().into()
-        }  
+        let home = self.get_str("HOME").unwrap_or_else(|_| "?".into());
+        compact_path(&env::var("PWD").unwrap(), &home, 0).into()
+    }
+
+    /// Export `USER` to the environment when it is missing, so that the default prompt always
+    /// has something to show. Falls back to the numeric user id if no name is available.
+    fn init_user_env(username: Option<String>) {
+        if env::var_os("USER").is_none() {
+            env::set_var("USER", username.unwrap_or_else(|| getuid().to_string()));
+        }
+    }
+
+    /// Read the host name again to update the `HOST` variable of the environment, which may
+    /// change during a long-lived session, and export `USER` if it was unset since. A `USER`
+    /// exported by the user is kept
+    pub fn refresh_host_user(&mut self) { Self::export_host_user(host_name(), sys::username) }
+
+    /// Export `HOST` and, when it is unset, `USER`, from the given sources. A missing host name
+    /// is shown as `?`. The user name is only looked up when `USER` is unset
+    fn export_host_user<F: FnOnce() -> Option<String>>(host: Option<String>, username: F) {
+        env::set_var("HOST", host.as_deref().unwrap_or("?"));
+        if env::var_os("USER").is_none() {
+            Self::init_user_env(username());
+        }
     }
 
-    /// Indicates if name is valid for functions and variables
+    /// Indicates if name is valid for functions and variables. It may not start with a digit,
+    /// so that it can't be mistaken for a positional argument such as `$1`
     #[must_use]
     pub fn is_valid_name(name: &str) -> bool {
         let mut iter = name.chars();
@@ -175,14 +687,60 @@ impl Variables {
             && iter.all(|c| c.is_alphanumeric() || c == '_')
     }
 
-    /// Remove a variable from the current scope. If the value can't be removed (it is outside a
-    /// function or does not exist), returns None
+    /// Remove a variable from the current namespace, or from the namespace designated by a
+    /// `super::` or `global::` qualifier. If the value can't be removed (it is outside the
+    /// namespace or does not exist), returns None
     pub fn remove(&mut self, name: &str) -> Option<Value<Rc<Function>>> {
-        if name.starts_with("super::") || name.starts_with("global::") {
+        let removed = match split_namespace(name) {
+            (Namespace::Any, bare) => self.scopes.remove_variable(bare),
+            (namespace, bare) => self.scopes.remove_from_namespace(bare, namespace),
+        };
+        if removed.is_some() {
+            self.notify(name);
+        }
+        removed
+    }
+
+    /// Remove a single element from a collection, given a subscripted reference: `map[key]` for
+    /// a map entry, or `array[index]` for an array element, where negative indices count from
+    /// the end and the following elements are shifted down. The variable itself is kept, even
+    /// if it is left empty. Returns false if there was no such element
+    pub fn remove_element(&mut self, reference: &str) -> bool {
+        let (name, key) = match split_subscript(reference) {
+            Some(parts) => parts,
+            None => return false,
+        };
+        match self.get_mut(name) {
+            Some(Value::Array(array)) => {
+                match key.parse().ok().and_then(|index| resolve_index(array.len(), index)) {
+                    Some(index) => {
+                        array.remove(index);
+                        true
+                    }
+                    None => false,
+                }
+            }
+            Some(Value::HashMap(map)) => map.remove(key).is_some(),
+            Some(Value::BTreeMap(map)) => map.remove(key).is_some(),
+            _ => false,
+        }
+    }
+
+    /// Rename the variable `old` to `new` in the scope where `old` is defined, rather than in the
+    /// current scope. Returns false if `old` does not exist in the current namespace
+    pub fn rename_variable(&mut self, old: &str, new: &str) -> bool {
+        let outer = |name: &str| name.starts_with("super::") || name.starts_with("global::");
+        if outer(old) || outer(new) {
             // Cannot mutate outer namespace
-            return None;
+            return false;
         }
-        self.0.remove_variable(name)
+        self.scopes.rename_variable(old, new.into())
+    }
+
+    /// Remove a variable like `remove`, returning the name of the type of the removed value, for
+    /// builtins to report what was unset
+    pub fn unset(&mut self, name: &str) -> Option<&'static str> {
+        self.remove(name).map(|value| type_name(&value))
     }
 
     /// Get the string value associated with a name on the current scope. This includes fetching
@@ -192,6 +750,21 @@ impl Variables {
         match name {
             "MWD" => return Ok(self.get_minimal_directory()),
             "SWD" => return Ok(self.get_simplified_directory()),
+            "$" => return self.get_str("PID"),
+            "!" => {
+                return Ok(self
+                    .last_background_pid
+                    .map(|pid| pid.to_string().into())
+                    .unwrap_or_default())
+            }
+            _ if !name.is_empty() && name.bytes().all(|b| b.is_ascii_digit()) => {
+                return match (self.get("args"), name.parse::<usize>()) {
+                    (Some(Value::Array(args)), Ok(index)) => {
+                        args.get(index).map(|arg| arg.to_string().into()).ok_or(Error::VarNotFound)
+                    }
+                    _ => Err(Error::VarNotFound),
+                };
+            }
             _ => (),
         }
         // If the parsed name contains the '::' pattern, then a namespace was
@@ -206,96 +779,481 @@ impl Variables {
                 Ok((c as char).to_string().into())
             }
             Some(("env", variable)) => Ok(env::var(variable).unwrap_or_default().into()),
+            // Reverse the value by graphemes, so that combining characters stay in place
+            Some(_) if name.ends_with("::reverse") => {
+                let value = self.get_str(&name[..name.len() - "::reverse".len()]);
+                Ok(value.unwrap_or_default().graphemes(true).rev().collect::<String>().into())
+            }
             Some(("super", _)) | Some(("global", _)) | None => {
                 // Otherwise, it's just a simple variable name.
                 match self.get(name) {
                     Some(Value::Str(val)) => Ok(val.clone()),
-                    _ => env::var(name).map(Into::into).map_err(|_| Error::VarNotFound),
+                    _ => self.env_var(name).map(Into::into).ok_or(Error::VarNotFound),
                 }
             }
             Some((..)) => Err(Error::UnsupportedNamespace(name.into())),
         }
     }
 
-    /// Get a variable on the current scope
+    /// Get a variable on the current scope. An element of an array can be accessed with
+    /// `name[index]`, where negative indices count from the end of the array
     #[must_use]
-    pub fn get(&self, mut name: &str) -> Option<&Value<Rc<Function>>> {
-        const GLOBAL_NS: &str = "global::";
-        const SUPER_NS: &str = "super::";
-
-        let namespace = if name.starts_with(GLOBAL_NS) {
-            name = &name[GLOBAL_NS.len()..];
-            // Go up as many namespaces as possible
-            Namespace::Global
-        } else if name.starts_with(SUPER_NS) {
-            let mut up = 0;
-            while name.starts_with(SUPER_NS) {
-                name = &name[SUPER_NS.len()..];
-                up += 1;
-            }
-
-            Namespace::Specific(up)
-        } else {
-            Namespace::Any
-        };
-        self.0.get(name, namespace)
+    pub fn get(&self, name: &str) -> Option<&Value<Rc<Function>>> {
+        let (namespace, name) = split_namespace(name);
+        if let Some((name, index)) = parse_array_reference(name) {
+            return match self.scopes.get(&*self.spelling(name), namespace) {
+                Some(Value::Array(array)) => {
+                    resolve_index(array.len(), index).and_then(|index| array.get(index))
+                }
+                _ => None,
+            };
+        }
+        self.scopes.get(&*self.spelling(name), namespace)
     }
 
-    /// Get a mutable access to a variable on the current scope
+    /// Indicates if `name` is defined, either as a shell variable, an alias or a function,
+    /// resolving namespace qualifiers like `get`, or as an environment variable for unqualified
+    /// names
     #[must_use]
-    pub fn get_mut(&mut self, name: &str) -> Option<&mut Value<Rc<Function>>> {
-        if name.starts_with("super::") || name.starts_with("global::") {
-            // Cannot mutate outer namespace
-            return None;
-        }
-        self.0.get_mut(name)
+    pub fn exists(&self, name: &str) -> bool {
+        self.get(name).is_some()
+            || (split_namespace(name).0 == Namespace::Any && self.env_var(name).is_some())
     }
-}
 
-impl Default for Variables {
+    /// Make variable names case-insensitive for ASCII letters, so that `$path` also finds `PATH`,
+    /// or case-sensitive again, which is the default
+    pub fn set_case_insensitive(&mut self, enabled: bool) {
+        self.scopes.set_flags(NOCASEVARS, enabled);
+    }
+
+    /// Indicates if variable names are case-insensitive
     #[must_use]
-    fn default() -> Self {
-        let mut map: Scopes<types::Str, Value<Rc<Function>>> = Scopes::with_capacity(64);
-        map.set("HISTORY_SIZE", "1000");
-        map.set("HISTFILE_SIZE", "100000");
-        map.set(
-            "PROMPT",
-            "${x::1B}]0;${USER}: \
-             ${PWD}${x::07}${c::0x55,bold}${USER}${c::default}:${c::0x4B}${SWD}${c::default}# \
-             ${c::reset}",
-        );
+    pub fn is_case_insensitive(&self) -> bool { self.scopes.has_flags(NOCASEVARS) }
 
-        // Set the PID, UID, and EUID variables.
-        map.set("PID", Value::Str(getpid().to_string().into()));
-        map.set("UID", Value::Str(getuid().to_string().into()));
-        map.set("EUID", Value::Str(geteuid().to_string().into()));
+    /// The name under which the variable `name` is stored. A variable spelled exactly like `name`
+    /// always wins. Otherwise, with case-insensitive names, the innermost variable spelled with
+    /// other cases is used, and among those of the same scope, the first in byte order
+    fn spelling<'b>(&self, name: &'b str) -> Cow<'b, str> {
+        if !self.is_case_insensitive() || self.scopes.index_scope_for_var(name).is_some() {
+            return Cow::Borrowed(name);
+        }
+        self.scopes
+            .scopes()
+            .find_map(|scope| {
+                scope
+                    .keys()
+                    .filter(|key| key.eq_ignore_ascii_case(name))
+                    .min_by(|a, b| a.as_str().cmp(b.as_str()))
+            })
+            .map_or(Cow::Borrowed(name), |key| Cow::Owned(key.to_string()))
+    }
 
-        map.set("CDPATH", Array::new());
+    /// Get an environment variable, ignoring the case of its name if variable names are
+    /// case-insensitive, with the same precedence as shell variables
+    fn env_var(&self, name: &str) -> Option<String> {
+        env::var(name).ok().or_else(|| {
+            if !self.is_case_insensitive() {
+                return None;
+            }
+            let mut vars: Vec<_> =
+                env::vars().filter(|(key, _)| key.eq_ignore_ascii_case(name)).collect();
+            vars.sort();
+            vars.into_iter().next().map(|(_, value)| value)
+        })
+    }
 
-        // Initialize the HOST variable
-        let mut host_name = [0_u8; 512];
-        env::set_var(
-            "HOST",
-            &gethostname(&mut host_name)
-                .ok()
-                .map_or_else(|| "?".into(), CStr::to_string_lossy)
-                .as_ref(),
-        );
+    /// Resolve a variable exactly `hops` namespace boundaries up from the current scope, the
+    /// same way `super::` does
+    #[must_use]
+    pub fn resolve_in_scope(&self, name: &str, hops: usize) -> Option<&Value<Rc<Function>>> {
+        self.scopes.get(name, Namespace::Specific(hops))
+    }
 
-        Self(map)
+    /// The character joining the elements of an array expanded as a single string: the first
+    /// character of `IFS`, or a space if `IFS` is not set or empty
+    #[must_use]
+    pub fn array_separator(&self) -> char {
+        self.get_str("IFS").ok().and_then(|ifs| ifs.chars().next()).unwrap_or(' ')
     }
-}
 
-#[cfg(test)]
-pub(crate) mod tests {
-    use super::*;
-    use crate::{
-        expansion::{Expander, Result, Select},
-        shell::IonError,
-    };
-    use serial_test_derive::serial;
+    /// Split `string` into an array on the characters of `IFS`, or on whitespace if `IFS` is not
+    /// set. If `IFS` is only made of whitespace, consecutive delimiters count as one and no
+    /// empty field is kept, else each delimiter separates a field, even an empty one
+    #[must_use]
+    pub fn split_by_ifs(&self, string: &str) -> types::Array<Rc<Function>> {
+        let ifs = self.get_str("IFS").unwrap_or_else(|_| " \t\n".into());
+        let collapse = ifs.chars().all(char::is_whitespace);
+        string
+            .split(|c: char| ifs.contains(c))
+            .filter(|word| !(collapse && word.is_empty()))
+            .map(|word| Value::Str(word.into()))
+            .collect()
+    }
 
-    pub struct VariableExpander(pub Variables);
+    /// Join the elements of the array `name` within `range` with `sep`. Negative bounds count
+    /// from the end of the array, and the upper bound is exclusive. Returns None if `name` is
+    /// not an array
+    #[must_use]
+    pub fn array_join_range(&self, name: &str, range: Range<isize>, sep: &str) -> Option<String> {
+        let words: Vec<_> =
+            self.array_range(name, range)?.iter().map(ToString::to_string).collect();
+        Some(words.join(sep))
+    }
+
+    /// Get the elements of an array within the range of a `name[start..end]` subscript, such as
+    /// `arr[1..3]`. Negative bounds count from the end of the array, the upper bound is exclusive
+    /// and either bound can be left out. Bounds out of the array are clamped. Returns None if
+    /// `name` has no range subscript or does not name an array
+    #[must_use]
+    pub fn get_array_slice(&self, name: &str) -> Option<types::Array<Rc<Function>>> {
+        let (name, range) = parse_array_slice(name)?;
+        self.array_range(name, range).map(<[_]>::to_vec)
+    }
+
+    /// The elements of the array `name` within `range`, with the bounds clamped to the array
+    fn array_range(&self, name: &str, range: Range<isize>) -> Option<&[Value<Rc<Function>>]> {
+        if let Some(Value::Array(array)) = self.get(name) {
+            let len = array.len() as isize;
+            let clamp = |index: isize| {
+                if index < 0 {
+                    (len + index).max(0) as usize
+                } else {
+                    index.min(len) as usize
+                }
+            };
+            let (start, end) = (clamp(range.start), clamp(range.end));
+            Some(&array[start..end.max(start)])
+        } else {
+            None
+        }
+    }
+
+    /// The entries of the map `name` sorted by key, with their values converted to strings, so
+    /// that hash maps are listed in a stable order. Returns None if `name` is not a map
+    #[must_use]
+    pub fn map_entries_sorted(&self, name: &str) -> Option<Vec<(types::Str, String)>> {
+        let mut entries: Vec<_> = match self.get(name)? {
+            Value::HashMap(map) => {
+                map.iter().map(|(key, value)| (key.clone(), value.to_string())).collect()
+            }
+            Value::BTreeMap(map) => {
+                map.iter().map(|(key, value)| (key.clone(), value.to_string())).collect()
+            }
+            _ => return None,
+        };
+        entries.sort_unstable_by(|(a, _), (b, _)| a.cmp(b));
+        Some(entries)
+    }
+
+    /// The number of elements of an array, of entries of a map, or of graphemes of a string.
+    /// Returns None for aliases, functions and unset names
+    #[must_use]
+    pub fn len_of(&self, name: &str) -> Option<usize> {
+        match self.get(name)? {
+            Value::Str(string) => Some(string.graphemes(true).count()),
+            Value::Array(array) => Some(array.len()),
+            Value::HashMap(map) => Some(map.len()),
+            Value::BTreeMap(map) => Some(map.len()),
+            Value::Alias(_) | Value::Function(_) | Value::None => None,
+        }
+    }
+
+    /// Indicates if the map `name` has an entry for `key`, without copying the map. Returns
+    /// false if `name` is not a map
+    #[must_use]
+    pub fn map_contains_key(&self, map: &str, key: &str) -> bool {
+        match self.get(map) {
+            Some(Value::HashMap(map)) => map.contains_key(key),
+            Some(Value::BTreeMap(map)) => map.contains_key(key),
+            _ => false,
+        }
+    }
+
+    /// Describe what `name` refers to: a shell variable with its type and value, an alias with
+    /// its definition, a function with its signature, or an environment variable
+    #[must_use]
+    pub fn describe(&self, name: &str) -> String {
+        match self.get(name) {
+            Some(Value::Alias(alias)) => format!("{}: alias to {}", name, &**alias),
+            Some(Value::Function(function)) => match function.description() {
+                Some(description) => {
+                    format!("{}: function {} -- {}", name, function.signature(), description)
+                }
+                None => format!("{}: function {}", name, function.signature()),
+            },
+            Some(value) if !matches!(value, Value::None) => {
+                format!("{}: {} variable: {}", name, type_name(value), value)
+            }
+            _ => match env::var(name) {
+                Ok(value) => format!("{}: environment variable: {}", name, value),
+                Err(_) => format!("{}: not found", name),
+            },
+        }
+    }
+
+    /// Start an expansion which resolves variables with `resolve_cached`. The cache must be
+    /// dropped at the end of the expansion, as it does not see later assignments
+    #[must_use]
+    pub fn begin_expansion(&self) -> ExpansionCache { ExpansionCache::default() }
+
+    /// Get a variable like `get`, memoizing the value in `cache` so that a name referenced
+    /// several times in a word is only looked up once. Namespaced names and array elements
+    /// are always looked up again
+    pub fn resolve_cached(
+        &self,
+        cache: &mut ExpansionCache,
+        name: &str,
+    ) -> Option<Value<Rc<Function>>> {
+        if !Self::is_valid_name(name) {
+            return self.get(name).cloned();
+        }
+        let walks = &mut cache.walks;
+        cache
+            .values
+            .entry(name.into())
+            .or_insert_with(|| {
+                *walks += 1;
+                self.get(name).cloned()
+            })
+            .clone()
+    }
+
+    /// Get a mutable access to a variable of the current namespace, or of the namespace
+    /// designated by a `super::` or `global::` qualifier
+    #[must_use]
+    pub fn get_mut(&mut self, name: &str) -> Option<&mut Value<Rc<Function>>> {
+        let (namespace, name) = split_namespace(name);
+        let name = self.spelling(name);
+        match namespace {
+            Namespace::Any => self.scopes.get_mut(&*name),
+            namespace => self.scopes.get_mut_in_namespace(&*name, namespace),
+        }
+    }
+}
+
+/// Split the `global::` or `super::` qualifiers from a name, into the namespace they designate
+pub(crate) fn split_namespace(mut name: &str) -> (Namespace, &str) {
+    const GLOBAL_NS: &str = "global::";
+    const SUPER_NS: &str = "super::";
+
+    let namespace = if name.starts_with(GLOBAL_NS) {
+        name = &name[GLOBAL_NS.len()..];
+        // Go up as many namespaces as possible
+        Namespace::Global
+    } else if name.starts_with(SUPER_NS) {
+        let mut up = 0;
+        while name.starts_with(SUPER_NS) {
+            name = &name[SUPER_NS.len()..];
+            up += 1;
+        }
+
+        Namespace::Specific(up)
+    } else {
+        Namespace::Any
+    };
+    (namespace, name)
+}
+
+/// Compact a path for display, as done for `SWD` and `MWD`: a leading `home` directory is replaced
+/// with `~`, and if more than two components remain, all of them but the last are cut to their
+/// first `per_component` graphemes, not counting the leading `.` of hidden directories. The
+/// components are not cut if `per_component` is 0
+#[must_use]
+pub fn compact_path(path: &str, home: &str, per_component: usize) -> String {
+    let path = if !home.is_empty() && std::path::Path::new(path).starts_with(home) {
+        path.replacen(home, "~", 1)
+    } else {
+        path.to_string()
+    };
+    let elements = path.split('/').filter(|s| !s.is_empty()).collect::<Vec<&str>>();
+    if per_component == 0 || elements.len() <= 2 {
+        return path;
+    }
+    let mut output = String::with_capacity(path.len());
+    for element in &elements[..elements.len() - 1] {
+        let hidden = element.starts_with('.');
+        output.extend(element.graphemes(true).take(per_component + usize::from(hidden)));
+        output.push('/');
+    }
+    output.push_str(elements[elements.len() - 1]);
+    output
+}
+
+/// Indicates if `name` is a valid variable name, once stripped of its namespace qualifier and of
+/// its subscript, such as `global::map[key]`
+fn is_valid_reference(name: &str) -> bool {
+    let (_, name) = split_namespace(name);
+    Variables::is_valid_name(split_subscript(name).map_or(name, |(name, _)| name))
+}
+
+/// Split a subscripted reference such as `name[key]` into the name and the subscript
+fn split_subscript(name: &str) -> Option<(&str, &str)> {
+    let open = name.find('[')?;
+    Some((&name[..open], name[open + 1..].strip_suffix(']')?))
+}
+
+/// Split a reference to an array element such as `name[-1]` into the name of the array and the
+/// index
+fn parse_array_reference(name: &str) -> Option<(&str, isize)> {
+    let (name, index) = split_subscript(name)?;
+    Some((name, index.parse().ok()?))
+}
+
+/// Split a range subscript such as `name[1..-1]` into the name of the array and the range. A
+/// missing bound stands for the start or the end of the array
+fn parse_array_slice(name: &str) -> Option<(&str, Range<isize>)> {
+    let (name, range) = split_subscript(name)?;
+    let (start, end) = range.split_once("..")?;
+    let bound = |bound: &str, default: isize| {
+        if bound.is_empty() {
+            Some(default)
+        } else {
+            bound.parse().ok()
+        }
+    };
+    Some((name, bound(start, 0)?..bound(end, isize::MAX)?))
+}
+
+/// The position of the element `index` of an array of `len` elements, where negative indices
+/// count from the end. Returns None if the index is out of the array
+fn resolve_index(len: usize, index: isize) -> Option<usize> {
+    let index = if index < 0 { len as isize + index } else { index };
+    usize::try_from(index).ok().filter(|&index| index < len)
+}
+
+/// Single-quote a value unless it is only made of characters which are never special to a shell
+fn quote(value: &str) -> Cow<'_, str> {
+    let plain = |c: char| c.is_alphanumeric() || "_-./:,+=%".contains(c);
+    if !value.is_empty() && value.chars().all(plain) {
+        Cow::Borrowed(value)
+    } else {
+        Cow::Owned(format!("'{}'", value.replace('\'', "'\\''")))
+    }
+}
+
+/// Append the JSON form of a value. Values without a JSON counterpart become null
+fn write_json_value(json: &mut String, value: &Value<Rc<Function>>) {
+    match value {
+        Value::Str(string) => write_json_string(json, string),
+        Value::Array(array) => {
+            json.push('[');
+            for (index, element) in array.iter().enumerate() {
+                if index != 0 {
+                    json.push(',');
+                }
+                write_json_value(json, element);
+            }
+            json.push(']');
+        }
+        Value::HashMap(map) => write_json_object(json, map.iter().collect::<BTreeMap<_, _>>()),
+        Value::BTreeMap(map) => write_json_object(json, map),
+        Value::Alias(_) | Value::Function(_) | Value::None => json.push_str("null"),
+    }
+}
+
+/// Append a JSON object with the given members, in order
+fn write_json_object<'a, I>(json: &mut String, members: I)
+where
+    I: IntoIterator<Item = (&'a types::Str, &'a Value<Rc<Function>>)>,
+{
+    json.push('{');
+    for (index, (name, value)) in members.into_iter().enumerate() {
+        if index != 0 {
+            json.push(',');
+        }
+        write_json_string(json, name);
+        json.push(':');
+        write_json_value(json, value);
+    }
+    json.push('}');
+}
+
+/// Append a JSON string, escaping the quotes, backslashes and control characters
+fn write_json_string(json: &mut String, string: &str) {
+    json.push('"');
+    for c in string.chars() {
+        match c {
+            '"' => json.push_str("\\\""),
+            '\\' => json.push_str("\\\\"),
+            '\n' => json.push_str("\\n"),
+            '\r' => json.push_str("\\r"),
+            '\t' => json.push_str("\\t"),
+            c if c < ' ' => {
+                json.push_str(&format!("\\u{:04x}", c as u32));
+            }
+            c => json.push(c),
+        }
+    }
+    json.push('"');
+}
+
+/// The name of the type of a value, as reported to the user
+const fn type_name<T>(value: &Value<T>) -> &'static str {
+    match value {
+        Value::Str(_) => "string",
+        Value::Alias(_) => "alias",
+        Value::Array(_) => "array",
+        Value::HashMap(_) => "hashmap",
+        Value::BTreeMap(_) => "btreemap",
+        Value::Function(_) => "function",
+        Value::None => "none",
+    }
+}
+
+/// The name of the host, if it can be determined
+fn host_name() -> Option<String> {
+    let mut host_name = [0_u8; 512];
+    gethostname(&mut host_name).ok().map(|name| name.to_string_lossy().into_owned())
+}
+
+impl Default for Variables {
+    #[must_use]
+    fn default() -> Self {
+        let mut map: Scopes<types::Str, Value<Rc<Function>>> = Scopes::with_capacity(64);
+        map.set("HISTORY_SIZE", "1000");
+        map.set("HISTFILE_SIZE", "100000");
+        map.set(
+            "PROMPT",
+            "${x::1B}]0;${USER}: \
+             ${PWD}${x::07}${c::0x55,bold}${USER}${c::default}:${c::0x4B}${SWD}${c::default}# \
+             ${c::reset}",
+        );
+        map.set("PROMPT2", "> ");
+
+        // Set the PID, UID, and EUID variables.
+        map.set("PID", Value::Str(getpid().to_string().into()));
+        map.set("UID", Value::Str(getuid().to_string().into()));
+        map.set("EUID", Value::Str(geteuid().to_string().into()));
+
+        map.set("CDPATH", Array::new());
+
+        // Initialize the USER variable
+        Self::init_user_env(sys::username());
+
+        // Initialize the HOST variable
+        env::set_var("HOST", host_name().as_deref().unwrap_or("?"));
+
+        Self {
+            scopes:               map,
+            last_background_pid:  None,
+            shadow_warning_limit: None,
+            ephemeral:            HashSet::new(),
+            change_hooks:         HashMap::new(),
+        }
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use crate::{
+        expansion::{Expander, Result, Select},
+        parser::lexers::assignments::{KeyBuf, Primitive},
+        shell::{flow_control::Statement, IonError},
+    };
+    use serial_test_derive::serial;
+
+    pub struct VariableExpander(pub Variables);
 
     impl Expander for VariableExpander {
         type Error = IonError;
@@ -335,6 +1293,17 @@ pub(crate) mod tests {
         assert!(VariableExpander(variables).expand_string("$FOO").is_err());
     }
 
+    #[test]
+    fn unknown_namespaces_are_told_apart_from_unset_variables() {
+        use crate::expansion::Error;
+        let variables = Variables::default();
+        assert!(matches!(variables.get_str("FOO"), Err(Error::VarNotFound)));
+        assert!(matches!(
+            variables.get_str("git::branch"),
+            Err(Error::UnsupportedNamespace(name)) if name == "git::branch"
+        ));
+    }
+
     #[test]
     fn set_var_and_expand_a_variable() {
         let mut variables = Variables::default();
@@ -343,6 +1312,1035 @@ pub(crate) mod tests {
         assert_eq!("BAR", &expanded);
     }
 
+    #[test]
+    fn resolve_variable_across_namespaces() {
+        let mut variables = Variables::default();
+        variables.set("FOO", "global");
+        variables.new_scope(true);
+        variables.set("FOO", "middle");
+        variables.new_scope(true);
+        variables.set("FOO", "inner");
+
+        assert_eq!(variables.resolve_in_scope("FOO", 0), Some(&Value::Str("inner".into())));
+        assert_eq!(variables.resolve_in_scope("FOO", 1), Some(&Value::Str("middle".into())));
+        assert_eq!(variables.resolve_in_scope("FOO", 2), Some(&Value::Str("global".into())));
+        assert_eq!(variables.resolve_in_scope("FOO", 3), None);
+    }
+
+    #[test]
+    fn positional_args_are_scoped() {
+        let mut variables = Variables::default();
+        variables.push_positional_args(vec!["script".into(), "a".into(), "b".into()]);
+        variables.new_scope(false);
+        variables.push_positional_args(vec!["fn".into(), "x".into(), "y".into(), "z".into()]);
+        assert_eq!(variables.positional_count(), 3);
+        assert_eq!(variables.get_str("2").unwrap(), "y");
+
+        variables.pop_scope();
+        assert_eq!(variables.positional_count(), 2);
+        assert_eq!(variables.get_str("2").unwrap(), "b");
+        assert!(variables.get_str("3").is_err());
+    }
+
+    #[test]
+    fn deep_shadowing_warns_when_enabled() {
+        let mut variables = Variables::default();
+        variables.set("FOO", "0");
+        for _ in 0..3 {
+            variables.new_scope(true);
+            variables.set("FOO", "shadow");
+        }
+        assert!(!variables.warn_on_deep_shadowing("FOO"));
+
+        variables.set_shadow_warning_limit(Some(2));
+        assert!(variables.warn_on_deep_shadowing("FOO"));
+        variables.set_shadow_warning_limit(Some(3));
+        assert!(!variables.warn_on_deep_shadowing("FOO"));
+    }
+
+    #[test]
+    fn assigning_over_a_function_warns_when_enabled() {
+        let mut variables = Variables::default();
+        let function = Function::new(None, "build".into(), Vec::new(), vec![Statement::End]);
+        variables.set("build", Value::Function(Rc::new(function)));
+        let value = Value::Str("x".into());
+        assert!(!variables.warn_on_function_clobber("build", &value));
+
+        variables.set("WARN_SHADOW", "1");
+        assert!(variables.warn_on_function_clobber("build", &value));
+        assert!(!variables.warn_on_function_clobber("other", &value));
+        let function = variables.get("build").cloned().unwrap();
+        assert!(!variables.warn_on_function_clobber("build", &function));
+    }
+
+    #[test]
+    fn shadowing_a_function_or_alias_notes_when_enabled() {
+        let mut variables = Variables::default();
+        let function = Function::new(None, "build".into(), Vec::new(), vec![Statement::End]);
+        variables.set("build", Value::Function(Rc::new(function)));
+        variables.set("ll", types::Alias("ls -l".into()));
+        let value = Value::Str("x".into());
+        assert!(!variables.note_on_shadowing("build", &value));
+
+        variables.set("SHADOW_WARN", "1");
+        assert!(variables.note_on_shadowing("build", &value));
+        assert!(variables.note_on_shadowing("ll", &value));
+        assert!(!variables.note_on_shadowing("other", &value));
+        let function = variables.get("build").cloned().unwrap();
+        assert!(!variables.note_on_shadowing("build", &function));
+        assert!(variables.note_on_shadowing("ll", &function));
+        assert!(!variables.note_on_shadowing("ll", &Value::Alias(types::Alias("ls".into()))));
+
+        variables.set("ll", "5");
+        assert_eq!(variables.get_str("ll").unwrap(), "5");
+    }
+
+    #[test]
+    fn array_join_range_slices_and_joins() {
+        let mut variables = Variables::default();
+        variables.set("PARTS", types_rs::array!["usr", "local", "share", "man", "man1"]);
+        variables.set("NAME", "value");
+        assert_eq!(variables.array_join_range("PARTS", 1..3, "/").unwrap(), "local/share");
+        assert_eq!(variables.array_join_range("PARTS", -3..-1, "/").unwrap(), "share/man");
+        assert_eq!(variables.array_join_range("PARTS", 3..10, "/").unwrap(), "man/man1");
+        assert_eq!(variables.array_join_range("PARTS", 3..1, "/").unwrap(), "");
+        assert_eq!(variables.array_join_range("NAME", 0..1, "/"), None);
+        assert_eq!(variables.array_join_range("MISSING", 0..1, "/"), None);
+    }
+
+    #[test]
+    #[serial]
+    fn ephemeral_variables_are_not_given_to_children() {
+        let mut variables = Variables::default();
+        env::set_var("ION_TEST_TOKEN", "secret");
+        env::set_var("ION_TEST_VISIBLE", "public");
+        variables.set("ION_TEST_TOKEN", "secret");
+        variables.mark_ephemeral("ION_TEST_TOKEN");
+
+        let child_env = variables.build_child_env();
+        assert!(variables.is_ephemeral("ION_TEST_TOKEN"));
+        assert!(child_env.iter().all(|(key, _)| key != "ION_TEST_TOKEN"));
+        assert!(child_env
+            .iter()
+            .any(|(key, value)| key == "ION_TEST_VISIBLE" && value == "public"));
+        assert_eq!(variables.get_str("ION_TEST_TOKEN").unwrap(), "secret");
+        env::remove_var("ION_TEST_VISIBLE");
+    }
+
+    #[test]
+    #[serial]
+    fn string_vars_are_exported_sorted_and_quoted() {
+        let mut variables = Variables::default();
+        env::set_var("ION_EXPORT_PLAIN", "/usr/bin:/bin");
+        env::set_var("ION_EXPORT_SHADOWED", "from env");
+        variables.set("ION_EXPORT_SHADOWED", "it's local");
+        variables.set("ION_EXPORT_EMPTY", "");
+        variables.new_scope(false);
+        variables.set("ION_EXPORT_SPACED", "a b$c");
+        variables.set_typed("ION_EXPORT_INNER", Value::Str("inner".into()));
+        variables.new_scope(true);
+        variables.set("ION_EXPORT_INNER", "innermost");
+
+        let exported = variables.export_string_vars();
+        let lines: Vec<_> = exported.lines().filter(|l| l.starts_with("ION_EXPORT_")).collect();
+        assert_eq!(
+            lines,
+            vec![
+                "ION_EXPORT_EMPTY=''",
+                "ION_EXPORT_INNER=innermost",
+                "ION_EXPORT_PLAIN=/usr/bin:/bin",
+                "ION_EXPORT_SHADOWED='it'\\''s local'",
+                "ION_EXPORT_SPACED='a b$c'",
+            ]
+        );
+        let names: Vec<_> = exported.lines().map(|l| l.split('=').next().unwrap()).collect();
+        assert!(names.windows(2).all(|pair| pair[0] < pair[1]));
+        env::remove_var("ION_EXPORT_PLAIN");
+        env::remove_var("ION_EXPORT_SHADOWED");
+    }
+
+    #[test]
+    #[serial]
+    fn ephemeral_variables_are_not_exported() {
+        let mut variables = Variables::default();
+        env::set_var("ION_EXPORT_TOKEN", "secret");
+        variables.set("ION_EXPORT_TOKEN", "secret");
+        variables.set("ION_EXPORT_KEPT", "kept");
+        variables.mark_ephemeral("ION_EXPORT_TOKEN");
+
+        let exported = variables.export_string_vars();
+        assert!(exported.lines().all(|line| !line.starts_with("ION_EXPORT_TOKEN=")));
+        assert!(exported.lines().any(|line| line == "ION_EXPORT_KEPT=kept"));
+    }
+
+    #[test]
+    #[serial]
+    fn environment_is_seeded_as_string_variables() {
+        env::set_var("ION_TEST_SEEDED", "from env");
+        env::set_var("PROMPT2", "env prompt");
+        let variables = Variables::with_env();
+        env::remove_var("ION_TEST_SEEDED");
+        env::remove_var("PROMPT2");
+
+        let find = |variables: &Variables, name: &str| {
+            variables
+                .string_vars()
+                .find(|(key, _)| key.as_str() == name)
+                .map(|(_, value)| value.clone())
+        };
+        assert_eq!(find(&variables, "ION_TEST_SEEDED"), Some("from env".into()));
+        assert_eq!(find(&variables, "PROMPT2"), Some("> ".into()));
+        assert_eq!(find(&Variables::default(), "ION_TEST_SEEDED"), None);
+    }
+
+    #[test]
+    fn variables_are_serialized_to_json() {
+        let mut variables = Variables::default();
+        variables.set("ION_JSON_STR", "say \"hi\"\n\\");
+        variables.set("ION_JSON_ARRAY", array!["1", "two"]);
+        let mut inner = types::HashMap::new();
+        inner.insert("leaf".into(), Value::Str("x".into()));
+        let mut map = types::BTreeMap::new();
+        map.insert("nested".into(), Value::HashMap(inner));
+        map.insert("list".into(), Value::Array(array!["a"]));
+        variables.set("ION_JSON_MAP", map);
+        variables.set("ION_JSON_ALIAS", types::Alias("ls -l".into()));
+        variables.set("ION_JSON_TOKEN", "secret");
+        variables.mark_ephemeral("ION_JSON_TOKEN");
+        variables.new_scope(true);
+        variables.set("ION_JSON_ARRAY", "shadowed");
+
+        let json: serde_json::Value = serde_json::from_str(&variables.to_json()).unwrap();
+        assert_eq!(json["ION_JSON_STR"], "say \"hi\"\n\\");
+        assert_eq!(json["ION_JSON_ARRAY"], "shadowed");
+        assert_eq!(json["ION_JSON_MAP"]["nested"]["leaf"], "x");
+        assert_eq!(json["ION_JSON_MAP"]["list"], serde_json::json!(["a"]));
+        assert!(json.get("ION_JSON_ALIAS").is_none());
+        assert!(json.get("ION_JSON_TOKEN").is_none());
+        assert!(json["HISTORY_SIZE"].is_string());
+    }
+
+    #[test]
+    fn array_subscripts_select_elements_and_slices() {
+        let mut variables = Variables::default();
+        variables.set("ARR", array!["a", "b", "c", "d"]);
+        assert_eq!(variables.get("ARR[1]"), Some(&Value::Str("b".into())));
+        assert_eq!(variables.get("ARR[-1]"), Some(&Value::Str("d".into())));
+        assert_eq!(variables.get("ARR[4]"), None);
+        assert_eq!(variables.get("ARR[-5]"), None);
+        assert_eq!(variables.get_array_slice("ARR[1..3]"), Some(array!["b", "c"]));
+        assert_eq!(variables.get_array_slice("ARR[-2..]"), Some(array!["c", "d"]));
+        assert_eq!(variables.get_array_slice("ARR[..-3]"), Some(array!["a"]));
+        assert_eq!(variables.get_array_slice("ARR[2..10]"), Some(array!["c", "d"]));
+        assert_eq!(variables.get_array_slice("ARR[5..10]"), Some(array![]));
+        assert_eq!(variables.get_array_slice("ARR[1]"), None);
+        assert_eq!(variables.get_array_slice("MISSING[0..1]"), None);
+    }
+
+    #[test]
+    fn strings_are_split_by_ifs() {
+        let mut variables = Variables::default();
+        assert_eq!(variables.split_by_ifs("  one \t two\nthree  "), array!["one", "two", "three"]);
+        variables.set("IFS", ":");
+        assert_eq!(variables.split_by_ifs("/bin::/usr/bin:"), array!["/bin", "", "/usr/bin", ""]);
+        variables.set("IFS", " ,");
+        assert_eq!(variables.split_by_ifs("a,,b c"), array!["a", "", "b", "c"]);
+    }
+
+    #[test]
+    fn array_separator_is_the_first_character_of_ifs() {
+        let mut variables = Variables::default();
+        assert_eq!(variables.array_separator(), ' ');
+        variables.set("IFS", ":;");
+        assert_eq!(variables.array_separator(), ':');
+        variables.set("IFS", "");
+        assert_eq!(variables.array_separator(), ' ');
+    }
+
+    #[test]
+    fn rename_variable_keeps_the_scope() {
+        let mut variables = Variables::default();
+        variables.set("OUTER", "outer");
+        variables.new_scope(false);
+        variables.set("INNER", "inner");
+        assert!(variables.rename_variable("INNER", "RENAMED_INNER"));
+        assert_eq!(variables.get_str("RENAMED_INNER").unwrap(), "inner");
+        assert!(variables.get("INNER").is_none());
+
+        assert!(variables.rename_variable("OUTER", "RENAMED_OUTER"));
+        variables.pop_scope();
+        assert!(variables.get("RENAMED_INNER").is_none());
+        assert_eq!(variables.get_str("RENAMED_OUTER").unwrap(), "outer");
+        assert!(variables.get("OUTER").is_none());
+        assert!(!variables.rename_variable("MISSING", "FOUND"));
+    }
+
+    #[test]
+    fn rename_variable_stops_at_namespaces() {
+        let mut variables = Variables::default();
+        variables.set("GLOBAL", "value");
+        variables.new_scope(true);
+        assert!(!variables.rename_variable("GLOBAL", "LOCAL"));
+        assert!(!variables.rename_variable("super::GLOBAL", "LOCAL"));
+        variables.pop_scope();
+        assert_eq!(variables.get_str("GLOBAL").unwrap(), "value");
+        assert!(variables.get("LOCAL").is_none());
+    }
+
+    #[test]
+    fn snapshot_is_not_affected_by_later_changes() {
+        let mut variables = Variables::default();
+        variables.set("GLOBAL", "global");
+        variables.new_scope(true);
+        variables.set("LOCAL", "local");
+        let snapshot = variables.snapshot();
+
+        variables.set("LOCAL", "changed");
+        variables.pop_scope();
+        variables.set("GLOBAL", "changed");
+        assert_eq!(snapshot.len(), 2);
+        assert_eq!(snapshot[1].get("LOCAL"), Some(&Value::Str("local".into())));
+
+        variables.restore(snapshot);
+        assert_eq!(variables.get_str("LOCAL").unwrap(), "local");
+        assert_eq!(variables.get_str("super::GLOBAL").unwrap(), "global");
+        // The function scope is still on a namespace boundary
+        assert!(!variables.rename_variable("GLOBAL", "RENAMED"));
+        variables.pop_scope();
+        assert!(variables.get("LOCAL").is_none());
+        assert_eq!(variables.get_str("GLOBAL").unwrap(), "global");
+    }
+
+    #[test]
+    fn remove_stops_at_namespaces_unless_qualified() {
+        let mut variables = Variables::default();
+        variables.set("OUTER", "outer");
+        variables.set("OTHER", "other");
+        variables.new_scope(true);
+        assert_eq!(variables.remove("OUTER"), None);
+        assert_eq!(variables.get_str("super::OUTER").unwrap(), "outer");
+
+        assert_eq!(variables.remove("global::OUTER"), Some(Value::Str("outer".into())));
+        assert_eq!(variables.remove("super::OTHER"), Some(Value::Str("other".into())));
+        assert_eq!(variables.remove("global::MISSING"), None);
+        variables.pop_scope();
+        assert!(variables.get("OUTER").is_none());
+        assert!(variables.get("OTHER").is_none());
+    }
+
+    #[test]
+    fn qualified_names_are_set_in_their_namespace() {
+        let mut variables = Variables::default();
+        variables.set("COUNT", "1");
+        variables.new_scope(true);
+        variables.set("OUTER", "outer");
+        variables.new_scope(true);
+        variables.set("global::COUNT", "5");
+        assert_eq!(variables.try_set("super::OUTER", "changed"), Ok(()));
+        variables.set("global::CREATED", "created");
+        assert!(variables.try_set("super::super::super::COUNT", "6").is_err());
+
+        variables.pop_scope();
+        assert_eq!(variables.get_str("OUTER").unwrap(), "changed");
+        variables.pop_scope();
+        assert_eq!(variables.get_str("COUNT").unwrap(), "5");
+        assert_eq!(variables.get_str("CREATED").unwrap(), "created");
+    }
+
+    #[test]
+    fn scope_guard_exits_its_scope() {
+        fn fill(variables: &mut Variables, early: bool) -> Option<()> {
+            let mut scope = variables.scoped(false);
+            scope.set("LOCAL", "local");
+            let mut nested = scope.scoped(true);
+            nested.new_scope(false);
+            if early {
+                return None;
+            }
+            nested.set("NESTED", "nested");
+            Some(())
+        }
+
+        let mut variables = Variables::default();
+        let depth = variables.scope_depth();
+        assert_eq!(fill(&mut variables, true), None);
+        assert_eq!(variables.scope_depth(), depth);
+        assert_eq!(fill(&mut variables, false), Some(()));
+        assert_eq!(variables.scope_depth(), depth);
+        assert!(variables.get("LOCAL").is_none());
+        assert!(variables.get("NESTED").is_none());
+
+        let result = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+            let _scope = variables.scoped(true);
+            panic!("failed in scope");
+        }));
+        assert!(result.is_err());
+        assert_eq!(variables.scope_depth(), depth);
+    }
+
+    #[test]
+    fn scope_vars_are_replaced_and_restored() {
+        let mut variables = Variables::default();
+        variables.set("OUTER", "outer");
+        variables.new_scope(false);
+        variables.set("LOCAL", "local");
+        variables.set("LIST", types_rs::array!["a", "b"]);
+
+        let mut temporary = HashMap::new();
+        temporary.insert("TEMP".into(), Value::Str("temp".into()));
+        let original = variables.replace_scope_vars(temporary);
+        assert_eq!(variables.get_str("TEMP").unwrap(), "temp");
+        assert!(variables.get("LOCAL").is_none());
+        assert_eq!(variables.get_str("OUTER").unwrap(), "outer");
+        variables.set("TEMP", "changed");
+        variables.set("OUTER", "changed");
+
+        let mut expected = HashMap::new();
+        expected.insert("LOCAL".into(), Value::Str("local".into()));
+        expected.insert("LIST".into(), Value::Array(types_rs::array!["a", "b"]));
+        assert_eq!(original, expected);
+        let temporary = variables.replace_scope_vars(original);
+        assert_eq!(temporary.get("TEMP"), Some(&Value::Str("changed".into())));
+        assert_eq!(variables.get_str("LOCAL").unwrap(), "local");
+        assert!(variables.get("TEMP").is_none());
+        assert_eq!(variables.get_str("OUTER").unwrap(), "changed");
+    }
+
+    #[test]
+    fn names_are_case_sensitive_by_default() {
+        let mut variables = Variables::default();
+        variables.set("EDITOR_NAME", "vim");
+        assert!(!variables.is_case_insensitive());
+        assert!(variables.get("editor_name").is_none());
+        variables.set("editor_name", "nano");
+        assert_eq!(variables.get_str("EDITOR_NAME").unwrap(), "vim");
+        assert_eq!(variables.get_str("editor_name").unwrap(), "nano");
+    }
+
+    #[test]
+    #[serial]
+    fn case_insensitive_names() {
+        let mut variables = Variables::default();
+        variables.set_case_insensitive(true);
+        variables.set("EDITOR_NAME", "vim");
+        assert_eq!(variables.get_str("editor_name").unwrap(), "vim");
+        variables.set("Editor_Name", "nano");
+        assert_eq!(variables.get_str("EDITOR_NAME").unwrap(), "nano");
+        assert!(variables.get("Editor_Name").is_some());
+        assert_eq!(variables.get("Editor_Name"), variables.get("EDITOR_NAME"));
+
+        // The exact spelling wins, then the innermost scope, then the first name in byte order
+        variables.set_typed("editor_name", Value::Str("emacs".into()));
+        assert_eq!(variables.get_str("editor_name").unwrap(), "emacs");
+        assert_eq!(variables.get_str("EDITOR_NAME").unwrap(), "nano");
+        assert_eq!(variables.get_str("Editor_name").unwrap(), "nano");
+        variables.new_scope(false);
+        variables.set_typed("eDITOR_NAME", Value::Str("ed".into()));
+        assert_eq!(variables.get_str("Editor_name").unwrap(), "ed");
+        variables.pop_scope();
+
+        env::set_var("ION_NOCASE_TEST", "from env");
+        assert_eq!(variables.get_str("ion_nocase_test").unwrap(), "from env");
+        variables.set_case_insensitive(false);
+        assert!(variables.get_str("ion_nocase_test").is_err());
+        assert!(variables.get("Editor_name").is_none());
+        env::remove_var("ION_NOCASE_TEST");
+    }
+
+    #[test]
+    fn merge_copies_the_current_scope() {
+        let mut other = Variables::default();
+        other.set("SHARED", "from other");
+        other.new_scope(false);
+        other.set("ONLY_OTHER", "other");
+        other.set("BOTH", "other");
+        other.set("ll", types::Alias("ls -l".into()));
+
+        let mut variables = Variables::default();
+        variables.set("BOTH", "mine");
+        variables.new_scope(false);
+        variables.set_typed("BOTH", Value::Str("mine".into()));
+        variables.merge(&other, false);
+        assert_eq!(variables.get_str("ONLY_OTHER").unwrap(), "other");
+        assert_eq!(variables.get_str("BOTH").unwrap(), "mine");
+        assert_eq!(variables.get("ll"), Some(&Value::Alias(types::Alias("ls -l".into()))));
+        assert!(variables.get("SHARED").is_none());
+
+        variables.merge(&other, true);
+        assert_eq!(variables.get_str("BOTH").unwrap(), "other");
+        variables.pop_scope();
+        assert_eq!(variables.get_str("BOTH").unwrap(), "mine");
+        assert!(variables.get("ONLY_OTHER").is_none());
+    }
+
+    #[test]
+    fn aliases_report_their_scope() {
+        let mut variables = Variables::default();
+        variables.set("ll", types::Alias("ls -l".into()));
+        variables.new_scope(false);
+        variables.new_scope(true);
+        variables.set("la", types::Alias("ls -a".into()));
+
+        let mut aliases: Vec<_> = variables
+            .aliases_with_scope()
+            .map(|(scope, key, alias)| (scope, key.as_str(), alias.as_str()))
+            .collect();
+        aliases.sort();
+        assert_eq!(aliases, vec![(0, "ll", "ls -l"), (2, "la", "ls -a")]);
+        variables.pop_scope();
+        variables.pop_scope();
+        assert_eq!(variables.aliases_with_scope().count(), 1);
+    }
+
+    #[test]
+    fn first_word_alias_matches_the_command_name() {
+        let mut variables = Variables::default();
+        variables.set("ll", types::Alias("ls -l".into()));
+        variables.set("name", "ll");
+
+        let (alias, end) = variables.first_word_alias("ll /tmp").unwrap();
+        assert_eq!(alias, types::Alias("ls -l".into()));
+        assert_eq!(end, 2);
+        let (alias, end) = variables.first_word_alias("  ll").unwrap();
+        assert_eq!(alias, types::Alias("ls -l".into()));
+        assert_eq!(end, 4);
+        assert_eq!(variables.first_word_alias("echo ll"), None);
+        assert_eq!(variables.first_word_alias("name"), None);
+        assert_eq!(variables.first_word_alias("   "), None);
+    }
+
+    #[test]
+    fn set_replaces_the_type_in_place() {
+        let mut variables = Variables::default();
+        variables.set("FOO", "string");
+        variables.new_scope(false);
+        variables.try_set("FOO", types_rs::array!["an", "array"]).unwrap();
+        variables.pop_scope();
+        assert_eq!(variables.get("FOO"), Some(&Value::Array(types_rs::array!["an", "array"])));
+    }
+
+    #[test]
+    #[serial]
+    fn existence_covers_shell_and_environment_variables() {
+        let mut variables = Variables::default();
+        variables.set("ION_TEST_LOCAL", "");
+        env::set_var("ION_TEST_ENV_ONLY", "1");
+
+        assert!(variables.exists("ION_TEST_LOCAL"));
+        assert!(variables.exists("global::ION_TEST_LOCAL"));
+        assert!(variables.exists("ION_TEST_ENV_ONLY"));
+        assert!(!variables.exists("global::ION_TEST_ENV_ONLY"));
+        assert!(!variables.exists("ION_TEST_UNDEFINED"));
+        env::remove_var("ION_TEST_ENV_ONLY");
+    }
+
+    #[test]
+    fn names_do_not_start_with_digits() {
+        assert!(Variables::is_valid_name("foo1"));
+        assert!(Variables::is_valid_name("_1"));
+        assert!(!Variables::is_valid_name("1foo"));
+        assert!(!Variables::is_valid_name("\u{661}foo"));
+        assert!(!Variables::is_valid_name(""));
+
+        let mut variables = Variables::default();
+        variables.set("1foo", "no");
+        assert_eq!(variables.get("1foo"), None);
+        // The special status variable is still set by the shell
+        variables.set("?", "1");
+        assert_eq!(variables.get("?"), Some(&Value::Str("1".into())));
+    }
+
+    #[test]
+    fn variables_with_invalid_names_are_not_created() {
+        let mut variables = Variables::default();
+        variables.set("VALID_name1", "yes");
+        assert_eq!(variables.get("VALID_name1"), Some(&Value::Str("yes".into())));
+        variables.set("with space", "no");
+        assert_eq!(variables.get("with space"), None);
+        assert!(variables.try_set("with space", "no").is_err());
+        variables.set("?", "0");
+        assert_eq!(variables.get("?"), Some(&Value::Str("0".into())));
+        variables.set("git-log", types::Alias("git log".into()));
+        assert!(variables.get("git-log").is_some());
+
+        assert!(is_valid_reference("map[key]"));
+        assert!(is_valid_reference("global::array[-1]"));
+        assert!(!is_valid_reference("bad name[key]"));
+    }
+
+    #[test]
+    fn shadowed_names_are_listed_once() {
+        let mut variables = Variables::default();
+        variables.set("SHADOWED", "outer");
+        variables.set("LIST", array!["outer"]);
+        variables.set("ll", types::Alias("ls -l".into()));
+        variables.set("GONE", "outer");
+        variables.new_scope(true);
+        variables.set("SHADOWED", "inner");
+        variables.set("LIST", array!["inner"]);
+        variables.set("ll", types::Alias("ls -la".into()));
+        variables.set("GONE", types::Alias("true".into()));
+
+        let strings: Vec<_> = variables
+            .string_vars()
+            .filter(|(name, _)| name.as_str() == "SHADOWED")
+            .map(|(_, value)| value.as_str())
+            .collect();
+        assert_eq!(strings, vec!["inner"]);
+        let arrays: Vec<_> = variables
+            .arrays()
+            .filter(|(name, _)| name.as_str() == "LIST")
+            .map(|(_, value)| value.clone())
+            .collect();
+        assert_eq!(arrays, vec![array!["inner"]]);
+        let aliases: Vec<_> =
+            variables.aliases().map(|(name, alias)| (name.as_str(), alias.as_str())).collect();
+        assert_eq!(aliases.len(), 2);
+        assert!(aliases.contains(&("ll", "ls -la")));
+        assert!(variables.string_vars().all(|(name, _)| name.as_str() != "GONE"));
+    }
+
+    #[test]
+    fn scopes_are_described_for_debugging() {
+        let mut variables = Variables::default();
+        variables.new_scope(true);
+        variables.set("LOCAL", "1");
+        variables.set("A_LOCAL", "2");
+        variables.new_scope(false);
+        variables.new_scope(true);
+        variables.pop_scope();
+
+        let description = variables.debug_scopes();
+        let lines: Vec<_> = description.lines().collect();
+        assert_eq!(lines.len(), 4);
+        assert!(lines[0].starts_with("scope 0 active: "));
+        assert!(lines[0].contains(" HISTORY_SIZE "));
+        assert_eq!(lines[1], "scope 1 active namespace: A_LOCAL LOCAL");
+        assert_eq!(lines[2], "scope 2 active: ");
+        assert_eq!(lines[3], "scope 3 inactive namespace: ");
+    }
+
+    #[test]
+    fn popped_scopes_are_not_listed() {
+        let mut variables = Variables::default();
+        variables.new_scope(false);
+        variables.set("stale", types::Alias("true".into()));
+        variables.set("STALE_LIST", array!["a"]);
+        variables.pop_scope();
+        assert!(variables.aliases().all(|(name, _)| name.as_str() != "stale"));
+        variables.new_scope(false);
+        assert!(variables.aliases().all(|(name, _)| name.as_str() != "stale"));
+        assert!(variables.arrays().all(|(name, _)| name.as_str() != "STALE_LIST"));
+        assert_eq!(variables.function_names(), Vec::<types::Str>::new());
+    }
+
+    #[test]
+    fn alias_and_function_names_are_owned() {
+        let mut variables = Variables::default();
+        variables.set("ll", types::Alias("ls -l".into()));
+        variables.new_scope(false);
+        variables.set("la", types::Alias("ls -a".into()));
+        variables.set("FOO", "not an alias");
+
+        let names = variables.alias_names();
+        let aliases: Vec<_> = variables.aliases().map(|(name, _)| name.clone()).collect();
+        assert_eq!(names, aliases);
+        for name in &names {
+            variables.remove(name);
+        }
+        assert_eq!(variables.aliases().count(), 0);
+        assert!(variables.function_names().is_empty());
+    }
+
+    #[test]
+    fn keys_are_filtered_by_pattern() {
+        let mut variables = Variables::default();
+        variables.set("PRIVATE", types::Alias("echo".into()));
+        variables.new_scope(true);
+        variables.set("PROMPT", "shadowed");
+        let mut names: Vec<_> = variables.keys_matching("PR*").map(|name| name.as_str()).collect();
+        names.sort_unstable();
+        assert_eq!(names, vec!["PROMPT", "PROMPT2"]);
+
+        let names: Vec<_> = variables.keys_matching("PROMPT").map(|name| name.as_str()).collect();
+        assert_eq!(names, vec!["PROMPT"]);
+        assert_eq!(variables.keys_matching("NO_SUCH*").count(), 0);
+    }
+
+    #[test]
+    fn clearing_locals_keeps_outer_scopes() {
+        let mut variables = Variables::default();
+        variables.set("OUTER", "outer");
+        variables.new_scope(true);
+        variables.set("LOCAL", "local");
+        variables.set("OUTER", "shadowed");
+        variables.clear_locals();
+
+        assert_eq!(variables.get("LOCAL"), None);
+        assert_eq!(variables.get("OUTER"), Some(&Value::Str("outer".into())));
+        assert_eq!(variables.scope_depth(), 2);
+        // The scope is still a namespace, so the outer variable is shadowed again
+        variables.set("OUTER", "shadowed");
+        variables.pop_scope();
+        assert_eq!(variables.get("OUTER"), Some(&Value::Str("outer".into())));
+    }
+
+    #[test]
+    fn single_elements_are_removed_from_collections() {
+        let mut variables = Variables::default();
+        variables.set("ARR", array!["a", "b", "c"]);
+        assert!(variables.remove_element("ARR[1]"));
+        assert_eq!(variables.get("ARR"), Some(&Value::Array(array!["a", "c"])));
+        assert!(variables.remove_element("ARR[-1]"));
+        assert!(!variables.remove_element("ARR[1]"));
+        assert!(!variables.remove_element("ARR[key]"));
+        assert!(variables.remove_element("ARR[0]"));
+        assert_eq!(variables.get("ARR"), Some(&Value::Array(array![])));
+
+        let mut map = types::HashMap::new();
+        map.insert("key".into(), Value::Str("value".into()));
+        variables.set("MAP", Value::HashMap(map));
+        assert!(!variables.remove_element("MAP[other]"));
+        assert!(variables.remove_element("MAP[key]"));
+        assert_eq!(variables.len_of("MAP"), Some(0));
+        assert!(!variables.remove_element("MAP"));
+        assert!(!variables.remove_element("MISSING[0]"));
+    }
+
+    #[test]
+    fn change_hooks_see_the_new_value() {
+        use std::cell::RefCell;
+        let changes = Rc::new(RefCell::new(Vec::new()));
+        let mut variables = Variables::default();
+        let recorded = changes.clone();
+        variables.on_change(
+            "FOO",
+            Box::new(move |name, value| {
+                recorded.borrow_mut().push(format!("{}={}", name, value));
+            }),
+        );
+        variables.set("FOO", "bar");
+        variables.append("FOO", "baz");
+        variables.set("OTHER", "ignored");
+        variables.new_scope(false);
+        variables.set_typed("FOO", Value::Str("shadow".into()));
+        variables.remove("FOO");
+        variables.remove("FOO");
+        variables.pop_scope();
+        assert_eq!(
+            *changes.borrow(),
+            ["FOO=bar", "FOO=barbaz", "FOO=shadow", "FOO=barbaz", "FOO="]
+        );
+    }
+
+    #[test]
+    fn defaults_do_not_replace_defined_variables() {
+        let mut variables = Variables::default();
+        assert!(!variables.set_if_unset("HISTORY_SIZE", "10"));
+        assert_eq!(variables.get("HISTORY_SIZE"), Some(&Value::Str("1000".into())));
+        variables.new_scope(true);
+        assert!(!variables.set_if_unset("HISTORY_SIZE", "10"));
+        assert!(variables.set_if_unset("ION_TEST_DEFAULT", "default"));
+        assert_eq!(variables.get("ION_TEST_DEFAULT"), Some(&Value::Str("default".into())));
+        assert!(!variables.set_if_unset("ION_TEST_DEFAULT", "other"));
+        assert_eq!(variables.get("ION_TEST_DEFAULT"), Some(&Value::Str("default".into())));
+        assert!(!variables.set_if_unset("not valid", "x"));
+    }
+
+    #[test]
+    fn append_extends_values_of_the_same_type() {
+        let mut variables = Variables::default();
+        variables.set("STR", "foo");
+        variables.append("STR", "bar");
+        assert_eq!(variables.get("STR"), Some(&Value::Str("foobar".into())));
+
+        variables.set("ARR", array!["a"]);
+        variables.append("ARR", array!["b", "c"]);
+        variables.append("ARR", "d");
+        assert_eq!(variables.get("ARR"), Some(&Value::Array(array!["a", "b", "c", "d"])));
+
+        let mut map = types::BTreeMap::new();
+        map.insert("kept".into(), Value::Str("1".into()));
+        map.insert("replaced".into(), Value::Str("2".into()));
+        variables.set("MAP", map);
+        let mut entries = types::BTreeMap::new();
+        entries.insert("replaced".into(), Value::Str("3".into()));
+        entries.insert("added".into(), Value::Str("4".into()));
+        variables.append("MAP", entries);
+        assert_eq!(
+            variables.map_entries_sorted("MAP"),
+            Some(vec![
+                ("added".into(), "4".to_string()),
+                ("kept".into(), "1".to_string()),
+                ("replaced".into(), "3".to_string()),
+            ])
+        );
+
+        variables.append("STR", array!["x"]);
+        assert_eq!(variables.get("STR"), Some(&Value::Array(array!["x"])));
+        variables.append("UNSET", "new");
+        assert_eq!(variables.get("UNSET"), Some(&Value::Str("new".into())));
+    }
+
+    #[test]
+    fn arrays_are_created_on_first_access() {
+        let mut variables = Variables::default();
+        variables.get_or_insert_array("ARR").unwrap().push("a".into());
+        variables.get_or_insert_array("ARR").unwrap().push("b".into());
+        assert_eq!(variables.get("ARR"), Some(&Value::Array(array!["a", "b"])));
+        variables.set("STR", "foo");
+        assert!(variables.get_or_insert_array("STR").is_none());
+        assert_eq!(variables.get("STR"), Some(&Value::Str("foo".into())));
+
+        variables.new_scope(true);
+        variables.get_or_insert_array("ARR").unwrap().push("c".into());
+        variables.get_or_insert_array("super::ARR").unwrap().push("d".into());
+        assert_eq!(variables.get("ARR"), Some(&Value::Array(array!["c"])));
+        variables.pop_scope();
+        assert_eq!(variables.get("ARR"), Some(&Value::Array(array!["a", "b", "d"])));
+    }
+
+    #[test]
+    fn array_elements_are_set_in_place() {
+        let mut variables = Variables::default();
+        variables.set("ARR", array!["a", "b", "c"]);
+        assert!(variables.set_array_element("ARR", 1, Value::Str("B".into())));
+        assert_eq!(variables.get("ARR"), Some(&Value::Array(array!["a", "B", "c"])));
+        assert!(variables.set_array_element("ARR", 5, Value::Str("f".into())));
+        assert_eq!(variables.get("ARR"), Some(&Value::Array(array!["a", "B", "c", "", "", "f"])));
+        assert!(variables.set_array_element("NEW", 0, Value::Str("x".into())));
+        assert_eq!(variables.get("NEW"), Some(&Value::Array(array!["x"])));
+        variables.set("STR", "foo");
+        assert!(!variables.set_array_element("STR", 0, Value::Str("x".into())));
+        assert_eq!(variables.get("STR"), Some(&Value::Str("foo".into())));
+    }
+
+    #[test]
+    fn length_depends_on_the_type() {
+        let mut variables = Variables::default();
+        variables.set("ARR", array!["a", "b", "c"]);
+        let mut map = types::HashMap::new();
+        map.insert("key".into(), Value::Str("value".into()));
+        variables.set("MAP", Value::HashMap(map));
+        variables.set("WORD", "cafe\u{301}s \u{1F44D}");
+        variables.set("ll", types::Alias("ls -l".into()));
+
+        assert_eq!(variables.len_of("ARR"), Some(3));
+        assert_eq!(variables.len_of("MAP"), Some(1));
+        assert_eq!(variables.len_of("WORD"), Some(7));
+        assert_eq!(variables.len_of("ll"), None);
+        assert_eq!(variables.len_of("MISSING"), None);
+    }
+
+    #[test]
+    fn map_keys_are_looked_up_in_place() {
+        let mut variables = Variables::default();
+        let mut hmap = types::HashMap::new();
+        hmap.insert("key".into(), Value::Str("value".into()));
+        variables.set("HMAP", Value::HashMap(hmap));
+        let mut bmap = types::BTreeMap::new();
+        bmap.insert("key".into(), Value::Str("value".into()));
+        variables.set("BMAP", Value::BTreeMap(bmap));
+        variables.set("STR", "key");
+
+        assert!(variables.map_contains_key("HMAP", "key"));
+        assert!(variables.map_contains_key("BMAP", "key"));
+        assert!(!variables.map_contains_key("HMAP", "other"));
+        assert!(!variables.map_contains_key("BMAP", "other"));
+        assert!(!variables.map_contains_key("STR", "key"));
+        assert!(!variables.map_contains_key("MISSING", "key"));
+    }
+
+    #[test]
+    fn map_entries_are_sorted_by_key() {
+        let mut variables = Variables::default();
+        let mut map = types::HashMap::new();
+        for (key, value) in &[("zulu", "3"), ("alpha", "1"), ("mike", "2")] {
+            map.insert((*key).into(), Value::Str((*value).into()));
+        }
+        variables.set("MAP", Value::HashMap(map));
+        assert_eq!(
+            variables.map_entries_sorted("MAP"),
+            Some(vec![
+                ("alpha".into(), "1".to_string()),
+                ("mike".into(), "2".to_string()),
+                ("zulu".into(), "3".to_string()),
+            ])
+        );
+
+        variables.set("ARRAY", types_rs::array!["a", "b"]);
+        assert_eq!(variables.map_entries_sorted("ARRAY"), None);
+        assert_eq!(variables.map_entries_sorted("UNDEFINED"), None);
+    }
+
+    #[test]
+    fn describe_each_kind_of_name() {
+        let mut variables = Variables::default();
+        variables.set("NAME", "value");
+        variables.set("LIST", types_rs::array!["a", "b"]);
+        variables.set("ll", types::Alias("ls -l".into()));
+        let args = vec![
+            KeyBuf { name: "x".into(), kind: Primitive::Integer },
+            KeyBuf { name: "label".into(), kind: Primitive::Str },
+        ];
+        let square = Function::new(None, "square".into(), args, vec![Statement::End]);
+        variables.set("square", Value::Function(Rc::new(square)));
+        let described =
+            Function::new(Some("greets".into()), "greet".into(), Vec::new(), vec![Statement::End]);
+        variables.set("greet", Value::Function(Rc::new(described)));
+        env::set_var("ION_DESCRIBE_TEST", "from env");
+
+        assert_eq!(variables.describe("NAME"), "NAME: string variable: value");
+        assert_eq!(variables.describe("LIST"), "LIST: array variable: a b");
+        assert_eq!(variables.describe("ll"), "ll: alias to ls -l");
+        assert_eq!(variables.describe("square"), "square: function square x:int label");
+        assert_eq!(variables.describe("greet"), "greet: function greet -- greets");
+        assert_eq!(
+            variables.describe("ION_DESCRIBE_TEST"),
+            "ION_DESCRIBE_TEST: environment variable: from env"
+        );
+        assert_eq!(variables.describe("ION_UNDEFINED_TEST"), "ION_UNDEFINED_TEST: not found");
+    }
+
+    #[test]
+    fn cached_lookups_walk_the_scopes_once() {
+        let mut variables = Variables::default();
+        variables.set("x", "value");
+        variables.new_scope(true);
+        let mut cache = variables.begin_expansion();
+        for _ in 0..3 {
+            assert_eq!(variables.resolve_cached(&mut cache, "x"), Some(Value::Str("value".into())));
+            assert_eq!(variables.resolve_cached(&mut cache, "undefined"), None);
+        }
+        assert_eq!(cache.walks, 2);
+
+        assert_eq!(
+            variables.resolve_cached(&mut cache, "global::x"),
+            Some(Value::Str("value".into()))
+        );
+        assert_eq!(cache.walks, 2);
+    }
+
+    #[test]
+    fn array_element_reference() {
+        let mut variables = Variables::default();
+        variables.set("FOO", types_rs::array!["first", "second", "last"]);
+        assert_eq!(variables.get("FOO[0]"), Some(&Value::Str("first".into())));
+        assert_eq!(variables.get("FOO[-1]"), Some(&Value::Str("last".into())));
+        assert_eq!(variables.get_str("FOO[-3]").unwrap(), "first");
+        assert_eq!(variables.get("FOO[3]"), None);
+        assert_eq!(variables.get("FOO[-4]"), None);
+        assert_eq!(variables.get("FOO[last]"), None);
+    }
+
+    #[test]
+    fn set_typed_stores_in_the_current_scope() {
+        let mut variables = Variables::default();
+        variables.set("FOO", "outer");
+        variables.new_scope(false);
+        variables.set_typed("FOO", Value::Array(types::Array::new()));
+        variables.set_typed("NUMBER", Value::Str("042".into()));
+        assert_eq!(variables.get("FOO"), Some(&Value::Array(types::Array::new())));
+        assert_eq!(variables.get_str("NUMBER").unwrap(), "042");
+        variables.pop_scope();
+        assert_eq!(variables.get_str("FOO").unwrap(), "outer");
+        assert_eq!(variables.get("NUMBER"), None);
+    }
+
+    #[test]
+    fn strict_types_rejects_type_changes() {
+        let mut variables = Variables::default();
+        variables.set("STRICT_TYPES", "1");
+        variables.set("FOO", "string");
+        assert_eq!(
+            variables.try_set("FOO", types_rs::array!["an", "array"]),
+            Err("cannot change the type of `FOO` from string to array".to_string())
+        );
+        assert_eq!(variables.get_str("FOO").unwrap(), "string");
+        variables.try_set("FOO", "other").unwrap();
+        variables.try_set("BAR", types_rs::array!["new"]).unwrap();
+        assert_eq!(variables.get_str("FOO").unwrap(), "other");
+    }
+
+    #[test]
+    fn reverse_keeps_graphemes_together() {
+        let mut variables = Variables::default();
+        variables.set("ASCII", "hello");
+        variables.set("ACCENT", "cafe\u{301}!");
+        assert_eq!(variables.get_str("ASCII::reverse").unwrap(), "olleh");
+        assert_eq!(variables.get_str("ACCENT::reverse").unwrap(), "!e\u{301}fac");
+        assert_eq!(variables.get_str("UNSET_VARIABLE::reverse").unwrap(), "");
+        assert_eq!(variables.get_str("c::reverse").unwrap(), "\x1b[7m");
+    }
+
+    #[test]
+    fn unset_reports_the_removed_type() {
+        let mut variables = Variables::default();
+        variables.set("FOO", types_rs::array!["bar", "baz"]);
+        assert_eq!(variables.unset("FOO"), Some("array"));
+        assert_eq!(variables.unset("FOO"), None);
+    }
+
+    #[test]
+    fn shell_and_background_pids_are_expanded() {
+        let mut variables = Variables::default();
+        assert_eq!(variables.get_str("$").unwrap(), variables.get_str("PID").unwrap());
+        assert_eq!(variables.get_str("!").unwrap(), "");
+
+        variables.set_last_background_pid(Pid::from_raw(4242));
+        let expanded = VariableExpander(variables).expand_string("$$ $!").unwrap().join(" ");
+        assert_eq!(expanded, format!("{} 4242", getpid()));
+    }
+
+    #[test]
+    #[serial]
+    fn user_is_set_from_username_source() {
+        env::remove_var("USER");
+        Variables::init_user_env(Some("ion".into()));
+        assert_eq!(Variables::default().get_str("USER").unwrap(), "ion");
+    }
+
+    #[test]
+    #[serial]
+    fn user_falls_back_to_uid() {
+        env::remove_var("USER");
+        Variables::init_user_env(None);
+        let user = Variables::default().get_str("USER").unwrap();
+        assert!(!user.is_empty());
+        assert_eq!(user, getuid().to_string().as_str());
+    }
+
+    #[test]
+    #[serial]
+    fn host_and_user_are_refreshed() {
+        let variables = Variables::default();
+        let user = env::var_os("USER");
+        env::remove_var("USER");
+        Variables::export_host_user(Some("first".into()), || Some("alice".into()));
+        assert_eq!(variables.get_str("HOST").unwrap(), "first");
+        assert_eq!(variables.get_str("USER").unwrap(), "alice");
+        // An exported USER is kept, without looking the user up
+        env::set_var("USER", "exported");
+        Variables::export_host_user(None, || panic!("USER is looked up while set"));
+        assert_eq!(variables.get_str("HOST").unwrap(), "?");
+        assert_eq!(variables.get_str("USER").unwrap(), "exported");
+        match user {
+            Some(user) => env::set_var("USER", user),
+            None => env::remove_var("USER"),
+        }
+    }
+
+    #[test]
+    fn paths_are_compacted() {
+        assert_eq!(compact_path("/home/ion/src/ion", "/home/ion", 0), "~/src/ion");
+        assert_eq!(compact_path("/home/ion/src/ion/lib", "/home/ion", 1), "~/s/i/lib");
+        assert_eq!(compact_path("/home/ion/.config/ion", "/home/ion", 1), "~/.c/ion");
+        assert_eq!(compact_path("/home/ionic/src", "/home/ion", 0), "/home/ionic/src");
+        assert_eq!(compact_path("/usr/local/share/doc", "/home/ion", 2), "us/lo/sh/doc");
+        assert_eq!(compact_path("/usr/local", "/home/ion", 1), "/usr/local");
+        assert_eq!(compact_path("/usr/.local/share", "", 1), "u/.l/share");
+    }
+
     #[test]
     #[serial]
     fn minimal_directory_var_should_compact_path() {
@@ -0,0 +1,42 @@
+use std::fmt;
+
+/// The version information of Ion, as printed by `ion --version`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct VersionInfo {
+    /// The version of the crate
+    pub version:  &'static str,
+    /// The target triple Ion was built for
+    pub platform: &'static str,
+    /// The git revision Ion was built from
+    pub revision: &'static str,
+}
+
+impl fmt::Display for VersionInfo {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "ion {} ({})\nrev {}", self.version, self.platform, self.revision)
+    }
+}
+
+/// Get the version, platform and revision Ion was built with
+#[must_use]
+pub const fn version_info() -> VersionInfo {
+    VersionInfo {
+        version:  env!("CARGO_PKG_VERSION"),
+        platform: env!("ION_TARGET"),
+        revision: env!("ION_GIT_REV"),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn version_info_is_populated() {
+        let info = version_info();
+        assert!(!info.version.is_empty());
+        assert!(!info.platform.is_empty());
+        assert!(!info.revision.is_empty());
+        assert!(info.to_string().starts_with(&format!("ion {} (", info.version)));
+    }
+}
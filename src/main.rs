@@ -1,14 +1,15 @@
 use self::binary::{builtins, InteractiveShell};
 use atty::Stream;
-use ion_shell::{BackgroundEvent, BuiltinMap, IonError, PipelineError, Shell, Value};
+use ion_shell::{BackgroundEvent, BuiltinMap, IonError, PipelineError, Shell, SyntaxError, Value};
 use liner::KeyBindings;
 use nix::{
     sys::signal::{self, SaFlags, SigAction, SigHandler, SigSet, Signal},
     unistd,
 };
 use std::{
+    fmt::Write,
     fs,
-    io::{stdin, BufReader},
+    io::{stdin, BufReader, Read},
     process,
     sync::Arc,
 };
@@ -35,11 +36,14 @@ struct CommandLineArgs {
     fake_interactive: bool,
     /// Force interactive mode
     interactive:      bool,
+    /// Source the login profiles before the init file
+    login:            bool,
     /// Print commands before execution
     print_commands:   bool,
     /// Shortcut layout. Valid options: "vi", "emacs"
     key_bindings:     Option<KeyBindingsWrapper>,
-    /// Evaluate given commands instead of reading from the commandline
+    /// Evaluate given commands instead of reading from the commandline. Commands given with
+    /// several -c options are run one after the other
     command:          Option<String>,
     /// Script arguments (@args). If the -c option is not specified,
     /// the first parameter is taken as a filename to execute
@@ -54,20 +58,19 @@ pub enum ParsingError {
     InvalidKeybinding,
 }
 
-fn version() -> String { include!(concat!(env!("OUT_DIR"), "/version_string")).to_string() }
-
-fn parse_args() -> Result<CommandLineArgs, ParsingError> {
+fn parse_args<I: IntoIterator<Item = String>>(args: I) -> Result<CommandLineArgs, ParsingError> {
     let mut arg_twice_set = false;
     let mut invalid_keybinding = false;
-    let mut args = env::args().skip(1);
+    let mut args = args.into_iter();
     let mut version = false;
     let mut help = false;
     let mut no_execute = false;
     let mut fake_interactive = false;
     let mut interactive = false;
+    let mut login = false;
     let mut print_commands = false;
     let mut key_bindings = None;
-    let mut command = None;
+    let mut command: Option<String> = None;
     let mut additional_arguments = Vec::new();
 
     while let Some(arg) = args.next() {
@@ -102,6 +105,12 @@ fn parse_args() -> Result<CommandLineArgs, ParsingError> {
                 }
                 interactive = true;
             }
+            "-l" | "--login" => {
+                if login {
+                    arg_twice_set = true;
+                }
+                login = true;
+            }
             "-x" => {
                 if print_commands {
                     arg_twice_set = true;
@@ -128,14 +137,18 @@ fn parse_args() -> Result<CommandLineArgs, ParsingError> {
                 }
             }
             "-c" => {
-                // convert Option<String< to Option<&str> due to type system limitation
-                if let Some(_p) = command.as_deref() {
-                    arg_twice_set = true
+                if let Some(next) = args.next() {
+                    command = Some(match command {
+                        Some(previous) => [previous, next].join("\n"),
+                        None => next,
+                    });
                 }
-                command = args.next();
             }
             _ => {
+                // The script and everything following it are the arguments of the script
                 additional_arguments.push(arg);
+                additional_arguments.extend(&mut args);
+                break;
             }
         }
     }
@@ -152,6 +165,7 @@ fn parse_args() -> Result<CommandLineArgs, ParsingError> {
         no_execute,
         fake_interactive,
         interactive,
+        login,
         print_commands,
         key_bindings,
         command,
@@ -171,8 +185,40 @@ fn set_unique_pid() -> nix::Result<()> {
     Ok(())
 }
 
+/// Render the syntax errors found in `source`, each with its position, its line and a caret
+/// under the column where it was found
+fn render_syntax_errors(name: &str, source: &str, errors: &[SyntaxError]) -> String {
+    let mut rendered = String::new();
+    for error in errors {
+        let line = source.lines().nth(error.line - 1).unwrap_or_default();
+        // Keep the tabs so that the caret is aligned whatever their width
+        let indent: String = line
+            .chars()
+            .take(error.column - 1)
+            .map(|c| if c == '\t' { '\t' } else { ' ' })
+            .collect();
+        let _ = writeln!(
+            rendered,
+            "ion: {}:{}:{}: {}\n    {}\n    {}^",
+            name, error.line, error.column, error.error, line, indent
+        );
+    }
+    rendered
+}
+
+/// Check the syntax of `source` without executing it, then exit with a non-zero status if any
+/// error was found
+fn check_syntax(shell: &Shell<'_>, name: &str, source: &str) -> ! {
+    let errors = shell.check_syntax(source);
+    if errors.is_empty() {
+        process::exit(0);
+    }
+    eprint!("{}", render_syntax_errors(name, source, &errors));
+    process::exit(1);
+}
+
 fn main() {
-    let parsedargs = parse_args();
+    let parsedargs = parse_args(env::args().skip(1));
     let command_line_args = match parsedargs {
         Ok(parsedargs) => parsedargs,
         Err(ParsingError::ArgTwiceSet) => {
@@ -190,7 +236,7 @@ fn main() {
         return;
     }
     if command_line_args.version {
-        println!("{}", version());
+        println!("{}", ion_shell::version_info());
         return;
     }
     if command_line_args.command.is_some() && !command_line_args.args.is_empty() {
@@ -227,6 +273,9 @@ fn main() {
         }
     })));
 
+    if command_line_args.login {
+        shell.variables_mut().set("LOGIN_SHELL", "1");
+    }
     shell.opts_mut().no_exec = command_line_args.no_execute;
     shell.opts_mut().grab_tty = stdin_is_a_tty;
     if command_line_args.print_commands {
@@ -251,6 +300,27 @@ fn main() {
         ),
     );
 
+    if command_line_args.no_execute {
+        if let Some(ref command) = command_line_args.command {
+            check_syntax(&shell, "-c", command);
+        } else if let Some(ref path) = script_path {
+            match fs::read_to_string(path) {
+                Ok(script) => check_syntax(&shell, path, &script),
+                Err(cause) => {
+                    println!("ion: could not execute '{}': {}", path, cause);
+                    process::exit(1);
+                }
+            }
+        } else if !stdin_is_a_tty && !command_line_args.interactive {
+            let mut script = String::new();
+            if let Err(cause) = stdin().read_to_string(&mut script) {
+                eprintln!("ion: could not read the standard input: {}", cause);
+                process::exit(1);
+            }
+            check_syntax(&shell, "stdin", &script);
+        }
+    }
+
     let err = if let Some(command) = command_line_args.command {
         shell.execute_command(command.as_bytes())
     } else if let Some(path) = script_path {
@@ -291,3 +361,40 @@ fn main() {
     }
     process::exit(shell.previous_status().as_os_code());
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(args: &[&str]) -> CommandLineArgs {
+        parse_args(args.iter().map(ToString::to_string)).unwrap()
+    }
+
+    #[test]
+    fn repeated_commands_are_joined() {
+        let args = parse(&["-c", "echo a", "-c", "echo b"]);
+        assert_eq!(args.command.as_deref(), Some("echo a\necho b"));
+        assert!(args.args.is_empty());
+    }
+
+    #[test]
+    fn arguments_after_the_script_are_not_parsed() {
+        let args = parse(&["-x", "script.ion", "-c", "echo a", "-n"]);
+        assert!(args.print_commands);
+        assert!(!args.no_execute);
+        assert_eq!(args.command, None);
+        assert_eq!(args.args, vec!["script.ion", "-c", "echo a", "-n"]);
+    }
+
+    #[test]
+    fn syntax_errors_show_their_location() {
+        let shell = Shell::new();
+        let script = "echo ok\nif test 1 = 1\n\techo a\nend\n  end\n";
+        let errors = shell.check_syntax(script);
+        assert_eq!(errors.len(), 1);
+        let rendered = render_syntax_errors("script.ion", script, &errors);
+        assert!(rendered.starts_with("ion: script.ion:5:3: block error: End found"));
+        assert!(rendered.ends_with("\n      end\n      ^\n"));
+        assert!(render_syntax_errors("-c", "echo ok", &shell.check_syntax("echo ok")).is_empty());
+    }
+}